@@ -1,97 +1,899 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    io::{self, Write},
+};
 
+// One register for every lowercase letter, `x` being the one the puzzle cares about. Adding to a
+// register wraps around on overflow, like it would in hardware.
+type Registers = [i32; 26];
+
+const X: usize = (b'x' - b'a') as usize;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Operand {
+    Value(i32),
+    Register(usize),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Instruction {
+    opcode: String,
+    operands: Vec<Operand>,
+}
+
+// What an instruction does once it has spent all its cycles. Returns a jump relative to the
+// instruction itself, or `None` to continue with the next one.
+type Execute = fn(&[Operand], &mut Registers) -> Option<i32>;
+
+struct Opcode {
+    cycles: u32,
+    operand_count: usize,
+    execute: Execute,
+    // The operand holding the relative jump, if the instruction can jump
+    jump_operand: Option<usize>,
+    // The operand written to, if any, which has to be a register
+    destination: Option<usize>,
+}
+
+struct InstructionSet {
+    opcodes: HashMap<String, Opcode>,
+}
+
+struct Cpu<'a> {
+    instruction_set: &'a InstructionSet,
+    program: Vec<Instruction>,
+    registers: Registers,
+    pc: usize,
+    // Number of cycles completed so far
+    cycle: u32,
+    // Cycles the current instruction still needs before it is done
+    remaining: Option<u32>,
+}
+
+// Everything that happened during a single cycle
+struct CycleEvent<'a> {
+    // The cycle number, starting from 1
+    cycle: u32,
+    pc: usize,
+    instruction: &'a Instruction,
+    // The registers during the cycle, and once it has finished
+    before: &'a Registers,
+    after: &'a Registers,
+}
+
+trait Observer {
+    fn observe(&mut self, event: &CycleEvent);
+}
+
+impl<F: FnMut(&CycleEvent)> Observer for F {
+    fn observe(&mut self, event: &CycleEvent) {
+        self(event)
+    }
+}
+
+// Which cycles to sample the signal strength during
 #[derive(Debug, PartialEq, Eq)]
-enum Op {
-    Noop,
-    Addx(i32),
+enum Schedule {
+    Cycles(BTreeSet<u32>),
+    Every {
+        first: u32,
+        interval: u32,
+        last: Option<u32>,
+    },
 }
 
-fn map_cycle_time(op: Op) -> (Op, u32) {
-    match op {
-        Op::Noop => (Op::Noop, 1),
-        Op::Addx(i) => (Op::Addx(i), 2),
+struct SignalSampler {
+    schedule: Schedule,
+    samples: Vec<(u32, i32)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CrtConfig {
+    width: usize,
+    height: usize,
+    // How many pixels wide the sprite is, centered on `x`
+    sprite_width: usize,
+    lit: char,
+    unlit: char,
+}
+
+struct Crt {
+    config: CrtConfig,
+    // Row by row, one pixel per cycle. Cycles past the end of the screen aren't drawn.
+    pixels: Vec<bool>,
+}
+
+// Writes every cycle as a CSV row, with the given registers before and after the cycle
+struct TraceWriter<W: Write> {
+    writer: W,
+    registers: Vec<usize>,
+    error: Option<io::Error>,
+}
+
+fn register_index(name: char) -> Option<usize> {
+    name.is_ascii_lowercase()
+        .then(|| usize::from(name as u8 - b'a'))
+}
+
+fn value(operand: Operand, registers: &Registers) -> i32 {
+    match operand {
+        Operand::Value(v) => v,
+        Operand::Register(r) => registers[r],
     }
 }
 
-fn parse(input: &str) -> Vec<(Op, u32)> {
-    use Op::*;
+impl InstructionSet {
+    fn new() -> Self {
+        InstructionSet {
+            opcodes: HashMap::new(),
+        }
+    }
+
+    fn register(&mut self, name: &str, cycles: u32, operand_count: usize, execute: Execute) {
+        assert!(0 < cycles, "\"{}\" has to take at least one cycle", name);
+        self.opcodes.insert(
+            name.to_string(),
+            Opcode {
+                cycles,
+                operand_count,
+                execute,
+                jump_operand: None,
+                destination: None,
+            },
+        );
+    }
+
+    fn mark_jump(&mut self, name: &str, operand: usize) {
+        self.opcodes.get_mut(name).unwrap().jump_operand = Some(operand);
+    }
+
+    fn mark_destination(&mut self, name: &str, operand: usize) {
+        self.opcodes.get_mut(name).unwrap().destination = Some(operand);
+    }
+
+    // The instructions understood by the handheld device from the puzzle
+    fn standard() -> Self {
+        let mut set = Self::new();
+        set.register("noop", 1, 0, |_, _| None);
+        set.register("addx", 2, 1, |ops, regs| {
+            regs[X] = regs[X].wrapping_add(value(ops[0], regs));
+            None
+        });
+        set
+    }
+
+    // The standard instructions, plus general purpose registers, jumps and branches
+    fn extended() -> Self {
+        let mut set = Self::standard();
+        set.register("set", 1, 2, |ops, regs| {
+            if let Operand::Register(r) = ops[0] {
+                regs[r] = value(ops[1], regs);
+            }
+            None
+        });
+        set.register("add", 2, 2, |ops, regs| {
+            if let Operand::Register(r) = ops[0] {
+                regs[r] = regs[r].wrapping_add(value(ops[1], regs));
+            }
+            None
+        });
+        set.register("jmp", 1, 1, |ops, regs| Some(value(ops[0], regs)));
+        set.register("jnz", 2, 2, |ops, regs| {
+            (value(ops[0], regs) != 0).then(|| value(ops[1], regs))
+        });
+        set.mark_jump("jmp", 0);
+        set.mark_jump("jnz", 1);
+        set.mark_destination("set", 0);
+        set.mark_destination("add", 0);
+        set
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Value(v) => write!(f, "{}", v),
+            Operand::Register(r) => write!(f, "{}", char::from(b'a' + *r as u8)),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opcode)?;
+        for operand in &self.operands {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Cpu<'a> {
+    fn new(instruction_set: &'a InstructionSet, program: Vec<Instruction>) -> Self {
+        let mut registers = [0; 26];
+        registers[X] = 1;
+        Cpu {
+            instruction_set,
+            program,
+            registers,
+            pc: 0,
+            cycle: 0,
+            remaining: None,
+        }
+    }
+
+    // Like `step`, but also reports the cycle to the observers
+    fn step_observed(&mut self, observers: &mut [&mut dyn Observer]) -> bool {
+        let pc = self.pc;
+        let before = self.registers;
+        if !self.step() {
+            return false;
+        }
+        let event = CycleEvent {
+            cycle: self.cycle,
+            pc,
+            instruction: &self.program[pc],
+            before: &before,
+            after: &self.registers,
+        };
+        for observer in observers.iter_mut() {
+            observer.observe(&event);
+        }
+        true
+    }
+
+    fn run(&mut self, observers: &mut [&mut dyn Observer]) {
+        while !self.is_halted() {
+            self.step_observed(observers);
+        }
+    }
+
+    fn is_halted(&self) -> bool {
+        self.current_opcode().is_none()
+    }
+
+    // The opcode of the instruction at the program counter. An instruction the instruction set
+    // doesn't know halts the program, just like running past the end.
+    fn current_opcode(&self) -> Option<&'a Opcode> {
+        let instruction = self.program.get(self.pc)?;
+        self.instruction_set.opcodes.get(&instruction.opcode)
+    }
+
+    // Runs a single cycle, returning false if the program has already finished
+    fn step(&mut self) -> bool {
+        let Some(opcode) = self.current_opcode() else {
+            return false;
+        };
+        let remaining = self.remaining.unwrap_or(opcode.cycles) - 1;
+        self.cycle += 1;
+        if 0 < remaining {
+            self.remaining = Some(remaining);
+            return true;
+        }
+
+        self.remaining = None;
+        let operands = &self.program[self.pc].operands;
+        let jump = (opcode.execute)(operands, &mut self.registers);
+        self.pc = match jump {
+            // Jumping before the start halts the program just like running past the end
+            Some(offset) => {
+                usize::try_from(self.pc as i64 + i64::from(offset)).unwrap_or(usize::MAX)
+            }
+            None => self.pc + 1,
+        };
+        true
+    }
+}
+
+impl Schedule {
+    // The schedule from the puzzle: cycle 20 and every 40 cycles after that, up to 220
+    fn puzzle() -> Self {
+        Schedule::Every {
+            first: 20,
+            interval: 40,
+            last: Some(220),
+        }
+    }
+
+    fn contains(&self, cycle: u32) -> bool {
+        match self {
+            Schedule::Cycles(cycles) => cycles.contains(&cycle),
+            Schedule::Every {
+                first,
+                interval,
+                last,
+            } => {
+                *first <= cycle
+                    && last.is_none_or(|last| cycle <= last)
+                    && (cycle - first).is_multiple_of(*interval.max(&1))
+            }
+        }
+    }
+
+    // Either a list of cycles like "20,60,100", or "first+interval" with an optional "..last"
+    // like "20+40..220"
+    fn parse(input: &str) -> Option<Self> {
+        if let Some((first, rest)) = input.split_once('+') {
+            let (interval, last) = match rest.split_once("..") {
+                Some((interval, last)) => (interval, Some(last.parse().ok()?)),
+                None => (rest, None),
+            };
+            Some(Schedule::Every {
+                first: first.parse().ok()?,
+                interval: interval.parse().ok()?,
+                last,
+            })
+        } else {
+            input
+                .split(',')
+                .map(|cycle| cycle.trim().parse().ok())
+                .collect::<Option<_>>()
+                .map(Schedule::Cycles)
+        }
+    }
+}
+
+impl SignalSampler {
+    fn new(schedule: Schedule) -> Self {
+        SignalSampler {
+            schedule,
+            samples: Vec::new(),
+        }
+    }
+
+    fn strength_sum(&self) -> i32 {
+        self.samples
+            .iter()
+            .map(|(cycle, x)| *cycle as i32 * x)
+            .sum()
+    }
+}
+
+impl Observer for SignalSampler {
+    fn observe(&mut self, event: &CycleEvent) {
+        // The register is read during the cycle, before the instruction finishes
+        if self.schedule.contains(event.cycle) {
+            self.samples.push((event.cycle, event.before[X]));
+        }
+    }
+}
+
+impl Default for CrtConfig {
+    // The screen from the puzzle
+    fn default() -> Self {
+        CrtConfig {
+            width: 40,
+            height: 6,
+            sprite_width: 3,
+            lit: '#',
+            unlit: '.',
+        }
+    }
+}
+
+impl CrtConfig {
+    // Draws rows of pixels with the lit and unlit characters
+    fn render<'a>(&self, scanlines: impl Iterator<Item = &'a [bool]>) -> String {
+        scanlines
+            .map(|row| {
+                row.iter()
+                    .map(|lit| if *lit { self.lit } else { self.unlit })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Crt {
+    fn new(config: CrtConfig) -> Self {
+        Crt {
+            config,
+            pixels: vec![false; config.width * config.height],
+        }
+    }
+
+    fn scanlines(&self) -> impl Iterator<Item = &[bool]> {
+        self.pixels.chunks(self.config.width.max(1))
+    }
+
+    // Exactly `height` lines, any pixels that were never drawn being unlit
+    fn image(&self) -> String {
+        self.config.render(self.scanlines())
+    }
+
+    // Plain (ASCII) bitmap of the screen, where 1 is black, so lit pixels are drawn dark
+    fn to_pbm(&self) -> String {
+        let pixels = self
+            .scanlines()
+            .map(|row| {
+                row.iter()
+                    .map(|lit| if *lit { "1" } else { "0" })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!(
+            "P1\n{} {}\n{}\n",
+            self.config.width, self.config.height, pixels
+        )
+    }
+}
+
+impl Observer for Crt {
+    fn observe(&mut self, event: &CycleEvent) {
+        let index = event.cycle as usize - 1;
+        if index >= self.pixels.len() {
+            return;
+        }
+        let pixel_pos = (index % self.config.width) as i64;
+        // For an even width, the extra pixel is on the right
+        let sprite_start = i64::from(event.before[X]) - (self.config.sprite_width as i64 - 1) / 2;
+        self.pixels[index] =
+            (sprite_start..sprite_start + self.config.sprite_width as i64).contains(&pixel_pos);
+    }
+}
+
+impl<W: Write> TraceWriter<W> {
+    fn new(mut writer: W, registers: Vec<usize>) -> io::Result<Self> {
+        write!(writer, "cycle,pc,instruction")?;
+        for r in &registers {
+            let name = Operand::Register(*r);
+            write!(writer, ",{}_before,{}_after", name, name)?;
+        }
+        writeln!(writer)?;
+        Ok(TraceWriter {
+            writer,
+            registers,
+            error: None,
+        })
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_row(&mut self, event: &CycleEvent) -> io::Result<()> {
+        write!(
+            self.writer,
+            "{},{},{}",
+            event.cycle, event.pc, event.instruction
+        )?;
+        for r in &self.registers {
+            write!(self.writer, ",{},{}", event.before[*r], event.after[*r])?;
+        }
+        writeln!(self.writer)
+    }
+}
+
+impl<W: Write> Observer for TraceWriter<W> {
+    fn observe(&mut self, event: &CycleEvent) {
+        // Keep the first error around, and stop writing once anything went wrong
+        if self.error.is_none() {
+            self.error = self.write_row(event).err();
+        }
+    }
+}
+
+// `x`, followed by every other register the program refers to
+fn used_registers(program: &[Instruction]) -> Vec<usize> {
+    let mut registers = vec![X];
+    for instruction in program {
+        for operand in &instruction.operands {
+            if let Operand::Register(r) = operand {
+                if !registers.contains(r) {
+                    registers.push(*r);
+                }
+            }
+        }
+    }
+    registers
+}
+
+fn parse_operand(input: &str) -> Option<Operand> {
+    match input.parse() {
+        Ok(v) => Some(Operand::Value(v)),
+        Err(_) if input.len() == 1 => register_index(input.chars().next()?).map(Operand::Register),
+        Err(_) => None,
+    }
+}
+
+// Checks the operands fit the instruction
+fn build_instruction(
+    opcode: &str,
+    operands: Vec<Operand>,
+    line: usize,
+    instruction_set: &InstructionSet,
+) -> Result<Instruction, String> {
+    let definition = instruction_set.opcodes.get(opcode).ok_or(format!(
+        "Unknown instruction \"{}\" on line {}",
+        opcode, line
+    ))?;
+    let expected = definition.operand_count;
+    if operands.len() != expected {
+        return Err(format!(
+            "\"{}\" takes {} operands, but got {} on line {}",
+            opcode,
+            expected,
+            operands.len(),
+            line
+        ));
+    }
+    if let Some(destination) = definition.destination {
+        if let Operand::Value(value) = operands[destination] {
+            return Err(format!(
+                "\"{}\" writes to operand {}, which has to be a register, but got {} on line {}",
+                opcode,
+                destination + 1,
+                value,
+                line
+            ));
+        }
+    }
+    Ok(Instruction {
+        opcode: opcode.to_string(),
+        operands,
+    })
+}
+
+fn parse(input: &str, instruction_set: &InstructionSet) -> Result<Vec<Instruction>, String> {
     input
         .lines()
-        .filter_map(|l| match &l[..] {
-            "noop" => Some(Noop),
-            _ if l.starts_with("addx") => Some(Addx(l.split_once(' ')?.1.parse().ok()?)),
-            _ => unimplemented!(),
+        .enumerate()
+        .map(|(i, l)| {
+            let mut words = l.split_whitespace();
+            let opcode = words.next().ok_or(format!("Line {} is empty", i + 1))?;
+            let operands = words
+                .map(|word| {
+                    parse_operand(word).ok_or(format!(
+                        "Invalid operand \"{}\" on line {}",
+                        word,
+                        i + 1
+                    ))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            build_instruction(opcode, operands, i + 1, instruction_set)
+        })
+        .collect()
+}
+
+struct Macro {
+    params: Vec<String>,
+    // The words on each line of the body
+    body: Vec<Vec<String>>,
+}
+
+// Everything the assembler knows about a program besides its instructions
+#[derive(Default)]
+struct Assembler {
+    constants: HashMap<String, i32>,
+    macros: HashMap<String, Macro>,
+    labels: HashMap<String, usize>,
+    // Instructions with macros expanded, but operands not yet resolved
+    statements: Vec<(usize, Vec<String>)>,
+    // How many macros have been expanded so far, to give each expansion its own labels
+    expansions: usize,
+}
+
+const MAX_MACRO_DEPTH: usize = 32;
+
+// Names of labels, constants and macros can't be mistaken for registers or numbers, and can't
+// contain '@', which is kept for the labels of macro expansions
+fn check_name(name: &str, line: usize) -> Result<(), String> {
+    if parse_operand(name).is_some() || name.starts_with('.') || name.contains('@') {
+        return Err(format!("Invalid name \"{}\" on line {}", name, line));
+    }
+    Ok(())
+}
+
+impl Assembler {
+    fn define_label(&mut self, name: &str, line: usize) -> Result<(), String> {
+        if self
+            .labels
+            .insert(name.to_string(), self.statements.len())
+            .is_some()
+        {
+            return Err(format!("Label \"{}\" defined twice on line {}", name, line));
+        }
+        Ok(())
+    }
+
+    fn expand(&mut self, line: usize, words: Vec<String>, depth: usize) -> Result<(), String> {
+        let mut words = words.into_iter().peekable();
+        while let Some(label) = words.next_if(|word| word.ends_with(':')) {
+            let label = &label[..label.len() - 1];
+            if depth == 0 {
+                check_name(label, line)?;
+            }
+            self.define_label(label, line)?;
+        }
+        let words: Vec<String> = words.collect();
+        let Some(name) = words.first() else {
+            return Ok(());
+        };
+        let Some(definition) = self.macros.get(name) else {
+            self.statements.push((line, words));
+            return Ok(());
+        };
+
+        if depth == MAX_MACRO_DEPTH {
+            return Err(format!("Macros nested too deeply on line {}", line));
+        }
+        if definition.params.len() != words.len() - 1 {
+            return Err(format!(
+                "Macro \"{}\" takes {} arguments, but got {} on line {}",
+                name,
+                definition.params.len(),
+                words.len() - 1,
+                line
+            ));
+        }
+        // Labels defined in the body only belong to this expansion, so get renamed to something
+        // no other label can be called
+        let mut labels = HashMap::new();
+        for body_words in &definition.body {
+            for label in body_words.iter().map_while(|word| word.strip_suffix(':')) {
+                labels.insert(label, format!("{}@{}", label, self.expansions));
+            }
+        }
+        self.expansions += 1;
+        let substitute = |word: &String| {
+            if let Some(i) = definition.params.iter().position(|p| p == word) {
+                words[i + 1].clone()
+            } else if let Some(renamed) = labels.get(word.as_str()) {
+                renamed.clone()
+            } else if let Some(renamed) = word.strip_suffix(':').and_then(|l| labels.get(l)) {
+                format!("{}:", renamed)
+            } else {
+                word.clone()
+            }
+        };
+        let body: Vec<Vec<String>> = definition
+            .body
+            .iter()
+            .map(|body_words| body_words.iter().map(substitute).collect())
+            .collect();
+        // Errors inside the expansion are reported on the line that used the macro
+        for body_words in body {
+            self.expand(line, body_words, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn resolve_operand(&self, word: &str, address: usize, line: usize) -> Result<Operand, String> {
+        if let Some(operand) = parse_operand(word) {
+            Ok(operand)
+        } else if let Some(value) = self.constants.get(word) {
+            Ok(Operand::Value(*value))
+        } else if let Some(target) = self.labels.get(word) {
+            // Labels become jumps relative to the instruction using them
+            Ok(Operand::Value(*target as i32 - address as i32))
+        } else {
+            Err(format!("Invalid operand \"{}\" on line {}", word, line))
+        }
+    }
+}
+
+// Turns source with comments (`;`), labels (`name:`), constants (`.const NAME value`) and macros
+// (`.macro name params..`, ended by `.endm`) into a program. Labels inside a macro are local to
+// each use of it.
+fn assemble(source: &str, instruction_set: &InstructionSet) -> Result<Vec<Instruction>, String> {
+    let mut assembler = Assembler::default();
+    let mut lines = source.lines().enumerate().map(|(i, l)| {
+        let code = l.split(';').next().unwrap();
+        let words: Vec<String> = code.split_whitespace().map(str::to_string).collect();
+        (i + 1, words)
+    });
+    while let Some((line, words)) = lines.next() {
+        match words.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            [".const", name, value] => {
+                check_name(name, line)?;
+                let value = assembler
+                    .constants
+                    .get(value)
+                    .copied()
+                    .or_else(|| value.parse().ok())
+                    .ok_or(format!("Invalid constant \"{}\" on line {}", value, line))?;
+                assembler.constants.insert(name.to_string(), value);
+            }
+            [".macro", name, ref params @ ..] => {
+                check_name(name, line)?;
+                let mut body = Vec::new();
+                loop {
+                    match lines.next() {
+                        Some((_, body_words)) if body_words == [".endm"] => break,
+                        // Labels in the body are checked here, as they're renamed once it's used
+                        Some((body_line, body_words)) => {
+                            body_words
+                                .iter()
+                                .map_while(|word| word.strip_suffix(':'))
+                                .try_for_each(|label| check_name(label, body_line))?;
+                            body.push(body_words);
+                        }
+                        None => return Err(format!("Macro on line {} has no .endm", line)),
+                    }
+                }
+                let params = params.iter().map(|p| p.to_string()).collect();
+                assembler
+                    .macros
+                    .insert(name.to_string(), Macro { params, body });
+            }
+            [directive, ..] if directive.starts_with('.') => {
+                return Err(format!(
+                    "Unknown directive \"{}\" on line {}",
+                    directive, line
+                ));
+            }
+            _ => assembler.expand(line, words, 0)?,
+        }
+    }
+
+    assembler
+        .statements
+        .iter()
+        .enumerate()
+        .map(|(address, (line, words))| {
+            let operands = words[1..]
+                .iter()
+                .map(|word| assembler.resolve_operand(word, address, *line))
+                .collect::<Result<Vec<_>, _>>()?;
+            build_instruction(&words[0], operands, *line, instruction_set)
         })
-        .map(map_cycle_time)
         .collect()
 }
 
-fn process_operation(ops: &mut VecDeque<(Op, u32)>) -> i32 {
-    if let Some(op) = ops.pop_front() {
-        match op {
-            (Op::Addx(x), t) => {
-                if t < 2 {
-                    return x;
-                } else {
-                    ops.push_front((Op::Addx(x), t - 1))
+// Source the assembler understands, with labels in place of jumps that land inside the program
+fn disassemble(program: &[Instruction], instruction_set: &InstructionSet) -> String {
+    let jump_target = |address: usize, instruction: &Instruction| {
+        let operand = instruction_set
+            .opcodes
+            .get(&instruction.opcode)?
+            .jump_operand?;
+        match instruction.operands.get(operand)? {
+            Operand::Value(offset) => usize::try_from(address as i64 + i64::from(*offset))
+                .ok()
+                .filter(|target| *target <= program.len())
+                .map(|target| (operand, target)),
+            Operand::Register(_) => None,
+        }
+    };
+    let targets: BTreeSet<usize> = program
+        .iter()
+        .enumerate()
+        .filter_map(|(address, instruction)| jump_target(address, instruction))
+        .map(|(_, target)| target)
+        .collect();
+
+    let mut source = String::new();
+    for address in 0..=program.len() {
+        if targets.contains(&address) {
+            source += &format!("l{}:\n", address);
+        }
+        let Some(instruction) = program.get(address) else {
+            break;
+        };
+        source += &format!("    {}", instruction.opcode);
+        let jump = jump_target(address, instruction);
+        for (i, operand) in instruction.operands.iter().enumerate() {
+            match jump {
+                Some((jump_operand, target)) if jump_operand == i => {
+                    source += &format!(" l{}", target)
                 }
+                _ => source += &format!(" {}", operand),
             }
-            _ => (),
         }
+        source += "\n";
     }
-    0
+    source
+}
+
+fn sample_signal_strengths(input: &str, schedule: Schedule) -> SignalSampler {
+    let instruction_set = InstructionSet::extended();
+    let mut cpu = Cpu::new(&instruction_set, parse(input, &instruction_set).unwrap());
+    let mut sampler = SignalSampler::new(schedule);
+    cpu.run(&mut [&mut sampler]);
+    sampler
 }
 
 fn process_signal_strengths(input: &str) -> i32 {
-    let mut ops = VecDeque::from(parse(input));
-    let mut register = 1;
-    let mut cycle = 1;
-    let mut cycles_to_check = vec![220, 180, 140, 100, 60, 20];
-    let mut ret = 0;
-    while !ops.is_empty() {
-        if let Some(checkpoint) = cycles_to_check.last().copied() {
-            if checkpoint == cycle {
-                // println!("Register is at {}", register);
-                ret += checkpoint * register;
-                cycles_to_check.pop();
-            }
-        }
-        register += process_operation(&mut ops);
-        cycle += 1;
-    }
-    ret
-}
-
-fn draw_image_from_instructions(input: &str) -> String {
-    let mut ops = VecDeque::from(parse(input));
-    let mut register: i32 = 1;
-    let mut cycle = 1;
-    let mut scanlines = Vec::new();
-    let mut scanline = String::new();
-    while !ops.is_empty() {
-        let pixel_pos = (cycle - 1) % 40;
-        if pixel_pos == 0 {
-            // println!("{}", scanline);
-            scanlines.push(scanline.clone());
-            scanline.clear();
-        }
-        // println!("{}", scanline);
-        // println!("Register: {}", register);
-        scanline.push(if register.abs_diff(pixel_pos) < 2 {
-            '#'
-        } else {
-            '.'
-        });
+    sample_signal_strengths(input, Schedule::puzzle()).strength_sum()
+}
 
-        register += process_operation(&mut ops);
-        cycle += 1;
-    }
-    scanlines.push(scanline);
+fn run_crt(input: &str, config: CrtConfig) -> Crt {
+    let instruction_set = InstructionSet::extended();
+    let mut cpu = Cpu::new(&instruction_set, parse(input, &instruction_set).unwrap());
+    let mut crt = Crt::new(config);
+    cpu.run(&mut [&mut crt]);
+    crt
+}
+
+fn draw_image_from_instructions(input: &str, config: CrtConfig) -> String {
+    let image = run_crt(input, config).image();
     // "Draw"
-    println!("{}", scanlines.join("\n"));
-    scanlines.join("\n")
+    println!("{}", image);
+    image
 }
 
+// Pixels of an image drawn with '#' for lit pixels, ignoring any blank lines
+fn parse_pixels(image: &str) -> Vec<Vec<bool>> {
+    image
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.chars().map(|c| c == '#').collect())
+        .collect()
+}
+
+// A letter's worth of pixels, row by row
+type Glyph = Vec<Vec<bool>>;
+
+// Splits scanlines into glyphs of the font's size
+fn split_glyphs<'a>(scanlines: impl Iterator<Item = &'a [bool]>) -> Vec<Glyph> {
+    let scanlines: Vec<&[bool]> = scanlines.collect();
+    let width = scanlines.iter().map(|l| l.len()).max().unwrap_or(0);
+    (0..width)
+        .step_by(5)
+        .map(|start| {
+            scanlines
+                .iter()
+                .map(|l| {
+                    l.get(start..(start + 4).min(l.len()))
+                        .unwrap_or(&[])
+                        .to_vec()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Reads the letters off the screen. Fails with every glyph that isn't a known letter,
+// along with its position.
+fn recognize_letters<'a>(
+    scanlines: impl Iterator<Item = &'a [bool]>,
+) -> Result<String, Vec<(usize, Glyph)>> {
+    let font: HashMap<Glyph, char> = split_glyphs(parse_pixels(FONT).iter().map(Vec::as_slice))
+        .into_iter()
+        .zip(FONT_LETTERS.chars())
+        .collect();
+    let mut letters = String::new();
+    let mut unrecognized = Vec::new();
+    for (i, glyph) in split_glyphs(scanlines).into_iter().enumerate() {
+        match font.get(&glyph) {
+            Some(letter) => letters.push(*letter),
+            None if !glyph.iter().flatten().any(|lit| *lit) => letters.push(' '),
+            None => unrecognized.push((i, glyph)),
+        }
+    }
+    if unrecognized.is_empty() {
+        Ok(letters)
+    } else {
+        Err(unrecognized)
+    }
+}
+
+fn write_trace<W: Write>(input: &str, writer: W) -> io::Result<W> {
+    let instruction_set = InstructionSet::extended();
+    let program = parse(input, &instruction_set).unwrap();
+    let mut trace = TraceWriter::new(writer, used_registers(&program))?;
+    Cpu::new(&instruction_set, program).run(&mut [&mut trace]);
+    trace.finish()
+}
+
+// The block letters the CRT draws, each 4 pixels wide with a column of space after it
+const FONT_LETTERS: &str = "ABCEFGHIJKLOPRSUZ";
+const FONT: &str =
+    ".##..###...##..####.####..##..#..#..###...##.#..#.#.....##..###..###...###.#..#.####.
+#..#.#..#.#..#.#....#....#..#.#..#...#.....#.#.#..#....#..#.#..#.#..#.#....#..#....#.
+#..#.###..#....###..###..#....####...#.....#.##...#....#..#.#..#.#..#.#....#..#...#..
+####.#..#.#....#....#....#.##.#..#...#.....#.#.#..#....#..#.###..###...##..#..#..#...
+#..#.#..#.#..#.#....#....#..#.#..#...#..#..#.#.#..#....#..#.#....#.#.....#.#..#.#....
+#..#.###...##..####.#.....###.#..#..###..##..#..#.####..##..#....#..#.###...##..####.";
+
 const SMALL_EXAMPLE: &str = "noop
 addx 3
 addx -5";
@@ -100,36 +902,510 @@ const LARGER_EXAMPLE: &str = include_str!("../inputs/10-example.txt");
 
 fn main() {
     const INPUT: &str = include_str!("../inputs/10.txt");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["--trace"] => {
+            write_trace(INPUT, io::stdout()).unwrap();
+            return;
+        }
+        ["--assemble", path] => {
+            let source = std::fs::read_to_string(path).unwrap();
+            match assemble(&source, &InstructionSet::extended()) {
+                Ok(program) => program.iter().for_each(|i| println!("{}", i)),
+                Err(error) => println!("{}", error),
+            }
+            return;
+        }
+        ["--disassemble"] => {
+            let instruction_set = InstructionSet::extended();
+            let program = parse(INPUT, &instruction_set).unwrap();
+            print!("{}", disassemble(&program, &instruction_set));
+            return;
+        }
+        ["--pbm", path] => {
+            std::fs::write(path, run_crt(INPUT, CrtConfig::default()).to_pbm()).unwrap();
+            println!("Wrote {}", path);
+            return;
+        }
+        ["--crt", width, height, sprite_width, ref glyphs @ ..] if glyphs.len() <= 2 => {
+            let mut config = CrtConfig {
+                width: width.parse().unwrap(),
+                height: height.parse().unwrap(),
+                sprite_width: sprite_width.parse().unwrap(),
+                ..CrtConfig::default()
+            };
+            if let [lit, rest @ ..] = glyphs {
+                config.lit = lit.chars().next().unwrap();
+                if let [unlit] = rest {
+                    config.unlit = unlit.chars().next().unwrap();
+                }
+            }
+            draw_image_from_instructions(INPUT, config);
+            return;
+        }
+        ["--sample", schedule] => {
+            let schedule = Schedule::parse(schedule).expect("Invalid schedule");
+            let sampler = sample_signal_strengths(INPUT, schedule);
+            for (cycle, x) in &sampler.samples {
+                println!(
+                    "Cycle {:4}: x = {:3}, strength {}",
+                    cycle,
+                    x,
+                    *cycle as i32 * x
+                );
+            }
+            println!("Sum of signal strengths: {}", sampler.strength_sum());
+            return;
+        }
+        _ => {}
+    }
     println!(
         "Sum of signal strengths: {}",
         process_signal_strengths(INPUT)
     );
 
     println!("Image from signal: ..");
-    draw_image_from_instructions(INPUT);
+    let crt = run_crt(INPUT, CrtConfig::default());
+    println!("{}", crt.image());
+    match recognize_letters(crt.scanlines()) {
+        Ok(letters) => println!("Letters on the screen: {}", letters),
+        Err(unrecognized) => {
+            for (i, glyph) in unrecognized {
+                let glyph = crt.config.render(glyph.iter().map(Vec::as_slice));
+                println!("Unrecognized glyph at position {}:\n{}", i, glyph);
+            }
+        }
+    }
 }
 
 #[test]
 fn parse_test() {
+    let instruction = |opcode: &str, operands: Vec<Operand>| Instruction {
+        opcode: opcode.to_string(),
+        operands,
+    };
+    assert_eq!(
+        parse(SMALL_EXAMPLE, &InstructionSet::standard()).unwrap(),
+        vec![
+            instruction("noop", vec![]),
+            instruction("addx", vec![Operand::Value(3)]),
+            instruction("addx", vec![Operand::Value(-5)]),
+        ]
+    );
     assert_eq!(
-        parse(SMALL_EXAMPLE),
-        vec![(Op::Noop, 1), (Op::Addx(3), 2), (Op::Addx(-5), 2)]
+        parse("jnz b -2", &InstructionSet::extended()).unwrap(),
+        vec![instruction(
+            "jnz",
+            vec![Operand::Register(1), Operand::Value(-2)]
+        )]
     );
+    assert_eq!(
+        parse("noop\njmp 2", &InstructionSet::standard()),
+        Err("Unknown instruction \"jmp\" on line 2".to_string())
+    );
+    assert!(parse("addx", &InstructionSet::standard()).is_err());
+    assert!(parse("addx 1 2", &InstructionSet::standard()).is_err());
+    assert!(parse("addx y2", &InstructionSet::standard()).is_err());
+    assert_eq!(
+        parse("set b 1\nset 5 3", &InstructionSet::extended()),
+        Err(
+            "\"set\" writes to operand 1, which has to be a register, but got 5 on line 2"
+                .to_string()
+        )
+    );
+    assert!(parse("add 1 x", &InstructionSet::extended()).is_err());
 }
 
 #[test]
-fn example_1() {
-    assert_eq!(process_signal_strengths(LARGER_EXAMPLE), 13140);
+#[should_panic(expected = "\"wait\" has to take at least one cycle")]
+fn register_without_cycles_test() {
+    InstructionSet::new().register("wait", 0, 0, |_, _| None);
 }
 
 #[test]
-fn example_2() {
+fn unknown_opcode_test() {
+    // A program from a bigger instruction set stops at the first instruction it can't run
+    let program = parse("addx 2\nset x 5\naddx 3", &InstructionSet::extended()).unwrap();
+    let instruction_set = InstructionSet::standard();
+    let mut cpu = Cpu::new(&instruction_set, program);
+    cpu.run(&mut []);
+    assert!(cpu.is_halted());
+    assert_eq!((cpu.pc, cpu.cycle, cpu.registers[X]), (1, 2, 3));
+    assert!(!cpu.step());
+}
+
+#[test]
+fn cpu_test() {
+    let instruction_set = InstructionSet::standard();
+    let mut cpu = Cpu::new(
+        &instruction_set,
+        parse(SMALL_EXAMPLE, &instruction_set).unwrap(),
+    );
+    let mut x_during_cycles = Vec::new();
+    while !cpu.is_halted() {
+        x_during_cycles.push(cpu.registers[X]);
+        assert!(cpu.step());
+    }
+    assert_eq!(x_during_cycles, vec![1, 1, 1, 4, 4]);
+    assert_eq!(cpu.registers[X], -1);
+    assert_eq!(cpu.cycle, 5);
+    assert!(!cpu.step());
+}
+
+#[test]
+fn extended_cpu_test() {
+    // Multiplies 6 by 7 into x, counting down b
+    let program = "set x 0
+set b 7
+add x 6
+add b -1
+jnz b -2";
+    let instruction_set = InstructionSet::extended();
+    let mut cpu = Cpu::new(&instruction_set, parse(program, &instruction_set).unwrap());
+    while cpu.step() {}
+    assert_eq!(cpu.registers[X], 42);
+    assert_eq!(cpu.cycle, 2 + 7 * (2 + 2 + 2));
+
+    // Registers wrap around rather than overflowing
+    let program = "addx 2147483647\nset b -2147483648\nadd b -1";
+    let mut cpu = Cpu::new(&instruction_set, parse(program, &instruction_set).unwrap());
+    cpu.run(&mut []);
+    assert_eq!(cpu.registers[X], i32::MIN);
+    assert_eq!(cpu.registers[1], i32::MAX);
+
+    let mut set = InstructionSet::standard();
+    set.register("double", 3, 0, |_, regs| {
+        regs[X] *= 2;
+        None
+    });
+    let mut cpu = Cpu::new(&set, parse("double\naddx 1\ndouble", &set).unwrap());
+    while cpu.step() {}
+    assert_eq!((cpu.registers[X], cpu.cycle), (6, 8));
+}
+
+#[test]
+fn observer_test() {
+    let instruction_set = InstructionSet::standard();
+    let mut cpu = Cpu::new(
+        &instruction_set,
+        parse(SMALL_EXAMPLE, &instruction_set).unwrap(),
+    );
+    let mut events = Vec::new();
+    let mut record = |event: &CycleEvent| {
+        events.push((
+            event.cycle,
+            event.instruction.to_string(),
+            event.before[X],
+            event.after[X],
+        ))
+    };
+    let mut sampler = SignalSampler::new(Schedule::parse("2,4").unwrap());
+    cpu.run(&mut [&mut record, &mut sampler]);
+    assert_eq!(
+        events,
+        vec![
+            (1, "noop".to_string(), 1, 1),
+            (2, "addx 3".to_string(), 1, 1),
+            (3, "addx 3".to_string(), 1, 4),
+            (4, "addx -5".to_string(), 4, 4),
+            (5, "addx -5".to_string(), 4, -1),
+        ]
+    );
+    assert_eq!(sampler.samples, vec![(2, 1), (4, 4)]);
+    assert_eq!(sampler.strength_sum(), 18);
+}
+
+#[test]
+fn schedule_test() {
+    assert_eq!(Schedule::parse("20+40..220"), Some(Schedule::puzzle()));
+    assert_eq!(
+        Schedule::parse("3, 1"),
+        Some(Schedule::Cycles(BTreeSet::from([1, 3])))
+    );
+    assert_eq!(Schedule::parse("20+"), None);
+    assert_eq!(Schedule::parse("a,b"), None);
+
+    let every = Schedule::parse("5+10").unwrap();
+    let sampled: Vec<_> = (1..50).filter(|cycle| every.contains(*cycle)).collect();
+    assert_eq!(sampled, vec![5, 15, 25, 35, 45]);
+
+    let samples = sample_signal_strengths(LARGER_EXAMPLE, Schedule::puzzle()).samples;
+    assert_eq!(
+        samples,
+        vec![
+            (20, 21),
+            (60, 19),
+            (100, 18),
+            (140, 21),
+            (180, 16),
+            (220, 18)
+        ]
+    );
+}
+
+#[test]
+fn trace_test() {
+    let csv = write_trace("addx 3\nadd b x", Vec::new()).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "cycle,pc,instruction,x_before,x_after,b_before,b_after
+1,0,addx 3,1,1,0,0
+2,0,addx 3,1,4,0,0
+3,1,add b x,4,4,0,0
+4,1,add b x,4,4,0,4
+"
+    );
+}
+
+#[test]
+fn recognize_letters_test() {
+    let recognize = |image| recognize_letters(parse_pixels(image).iter().map(Vec::as_slice));
+    assert_eq!(recognize(FONT), Ok(FONT_LETTERS.to_string()));
+
     let image = "
-##..##..##..##..##..##..##..##..##..##..
+####.###...##..#..#.
+#....#..#.#..#.#..#.
+###..#..#.#..#.####.
+#....###..####.#..#.
+#....#.#..#..#.#..#.
+#....#..#.#..#.#..#.";
+    assert_eq!(recognize(image), Ok("FRAH".to_string()));
+
+    // Recognition only looks at the pixels, however they are drawn
+    let config = CrtConfig {
+        width: 20,
+        height: 6,
+        lit: '█',
+        unlit: ' ',
+        ..CrtConfig::default()
+    };
+    let crt = Crt {
+        config,
+        pixels: parse_pixels(image).concat(),
+    };
+    assert!(crt.image().starts_with("████ ███   ██  █  █ "));
+    assert_eq!(recognize_letters(crt.scanlines()), Ok("FRAH".to_string()));
+
+    let image = "
+####......#..#
+...#......#..#
+..#.......####
+.#........#..#
+#.........#..#
+####......#.##";
+    let unrecognized = recognize(image).unwrap_err();
+    assert_eq!(unrecognized.len(), 1);
+    let (position, glyph) = &unrecognized[0];
+    assert_eq!(*position, 2);
+    assert_eq!(
+        CrtConfig::default().render(glyph.iter().map(Vec::as_slice)),
+        "#..#\n#..#\n####\n#..#\n#..#\n#.##"
+    );
+    assert!(recognize(LARGER_EXAMPLE_IMAGE).is_err());
+}
+
+#[test]
+fn crt_config_test() {
+    let config = CrtConfig {
+        width: 8,
+        height: 3,
+        sprite_width: 4,
+        lit: '@',
+        unlit: ' ',
+    };
+    // x stays at 1, then moves to 5 from cycle 11 until the program ends after cycle 16, leaving
+    // the last scanline undrawn
+    let program = "noop\n".repeat(8) + "addx 4\n" + &"noop\n".repeat(6);
+    let crt = run_crt(&program, config);
+    assert_eq!(crt.image(), "@@@@    \n@@  @@@@\n        ");
+    assert_eq!(
+        crt.to_pbm(),
+        "P1
+8 3
+1 1 1 1 0 0 0 0
+1 1 0 0 1 1 1 1
+0 0 0 0 0 0 0 0
+"
+    );
+
+    // Cycles past the bottom of the screen are not drawn
+    let image = draw_image_from_instructions(
+        LARGER_EXAMPLE,
+        CrtConfig {
+            height: 2,
+            ..CrtConfig::default()
+        },
+    );
+    assert_eq!(image.lines().count(), 2);
+    assert!(LARGER_EXAMPLE_IMAGE.starts_with(&image));
+}
+
+#[test]
+fn assemble_test() {
+    let instruction_set = InstructionSet::extended();
+    let source = "
+; Multiplies A by B into x
+.const A 6
+.const B 7
+.macro clear r
+    set r 0
+.endm
+.macro mul r n
+    set c n
+loop: add r A   ; one more lot of A
+    add c -1
+    jnz c loop
+.endm
+
+    clear x
+    mul x B
+end:";
+    let program = assemble(source, &instruction_set).unwrap();
+    assert_eq!(
+        program,
+        parse(
+            "set x 0\nset c 7\nadd x 6\nadd c -1\njnz c -2",
+            &instruction_set
+        )
+        .unwrap()
+    );
+    let mut cpu = Cpu::new(&instruction_set, program);
+    cpu.run(&mut []);
+    assert_eq!(cpu.registers[X], 42);
+
+    let errors = [
+        ("addx 1\nfoo 2", "Unknown instruction \"foo\" on line 2"),
+        ("jmp nowhere", "Invalid operand \"nowhere\" on line 1"),
+        ("a: noop", "Invalid name \"a\" on line 1"),
+        ("top:\ntop: noop", "Label \"top\" defined twice on line 2"),
+        ("top@0: noop", "Invalid name \"top@0\" on line 1"),
+        (
+            ".macro mac\nnoop\nx: noop\n.endm",
+            "Invalid name \"x\" on line 3",
+        ),
+        (".macro mac\nnoop", "Macro on line 1 has no .endm"),
+        (
+            ".macro mac a\naddx a\n.endm\nmac",
+            "Macro \"mac\" takes 1 arguments, but got 0 on line 4",
+        ),
+        (
+            ".macro mac\nmac\n.endm\nmac",
+            "Macros nested too deeply on line 4",
+        ),
+        (".org 5", "Unknown directive \".org\" on line 1"),
+    ];
+    for (source, error) in errors {
+        assert_eq!(assemble(source, &instruction_set), Err(error.to_string()));
+    }
+}
+
+#[test]
+fn assemble_macro_labels_test() {
+    let instruction_set = InstructionSet::extended();
+    let source = "
+.macro wait n
+    set c n
+loop: add c -1
+    jnz c loop
+.endm
+    wait 3
+    wait 4";
+    let program = assemble(source, &instruction_set).unwrap();
+    assert_eq!(
+        program,
+        parse(
+            "set c 3\nadd c -1\njnz c -1\nset c 4\nadd c -1\njnz c -1",
+            &instruction_set
+        )
+        .unwrap()
+    );
+
+    // Labels from outside are still reachable from inside, but not the other way around
+    let source = ".macro skip\n    jmp end\nover: noop\n.endm\n    skip\nend:";
+    assert!(assemble(source, &instruction_set).is_ok());
+    let source = ".macro mark\nover: noop\n.endm\n    mark\n    jmp over";
+    assert_eq!(
+        assemble(source, &instruction_set),
+        Err("Invalid operand \"over\" on line 5".to_string())
+    );
+}
+
+#[test]
+fn disassemble_test() {
+    let instruction_set = InstructionSet::extended();
+    let program = parse(
+        "set b 3\nadd x 2\nadd b -1\njnz b -2\njmp 2\njmp -9",
+        &instruction_set,
+    )
+    .unwrap();
+    let source = disassemble(&program, &instruction_set);
+    assert_eq!(
+        source,
+        "    set b 3
+l1:
+    add x 2
+    add b -1
+    jnz b l1
+    jmp l6
+    jmp -9
+l6:
+"
+    );
+    assert_eq!(assemble(&source, &instruction_set).unwrap(), program);
+
+    let program = parse(LARGER_EXAMPLE, &instruction_set).unwrap();
+    let source = disassemble(&program, &instruction_set);
+    assert_eq!(assemble(&source, &instruction_set).unwrap(), program);
+}
+
+#[test]
+fn assembled_image_test() {
+    // Draws a checkerboard by moving the sprite 2 pixels every 2 cycles, then pulling it back
+    // at the end of every scanline
+    let source = "
+.macro stride
+    addx 2
+.endm
+.macro scanline back
+    stride
+    stride
+    stride
+    addx back
+.endm
+    scanline -7
+    scanline -5
+    scanline 0";
+    let instruction_set = InstructionSet::standard();
+    let program = assemble(source, &instruction_set).unwrap();
+    let config = CrtConfig {
+        width: 8,
+        height: 3,
+        sprite_width: 1,
+        ..CrtConfig::default()
+    };
+    let mut cpu = Cpu::new(&instruction_set, program);
+    let mut crt = Crt::new(config);
+    cpu.run(&mut [&mut crt]);
+    assert_eq!(crt.image(), ".#.#.#.#\n#.#.#.#.\n.#.#.#.#");
+}
+
+#[test]
+fn example_1() {
+    assert_eq!(process_signal_strengths(LARGER_EXAMPLE), 13140);
+}
+
+#[cfg(test)]
+const LARGER_EXAMPLE_IMAGE: &str = "##..##..##..##..##..##..##..##..##..##..
 ###...###...###...###...###...###...###.
 ####....####....####....####....####....
 #####.....#####.....#####.....#####.....
 ######......######......######......####
 #######.......#######.......#######.....";
-    assert_eq!(draw_image_from_instructions(LARGER_EXAMPLE), image);
+
+#[test]
+fn example_2() {
+    assert_eq!(
+        draw_image_from_instructions(LARGER_EXAMPLE, CrtConfig::default()),
+        LARGER_EXAMPLE_IMAGE
+    );
 }
@@ -1,107 +1,336 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    iter::Peekable,
+    str::CharIndices,
+};
 
-use evalexpr::context_map;
 use itertools::Itertools;
-use regex::Regex;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Expr {
+    Old,
+    Num(u64),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+}
+
+// The operations the puzzle uses get their own variants, so they don't have to walk a tree
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Operation {
+    Square,
+    Add(u64),
+    Mul(u64),
+    Expr(Expr),
+}
+
+// How worry levels are kept in check after every inspection
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Relief {
+    DivideBy(u64),
+    // Modulo the LCM of every monkey's divisor, which doesn't change where items are thrown
+    Modulo,
+    None,
+}
+
+struct SimulationConfig {
+    rounds: usize,
+    relief: Relief,
+    // Monkey business is the product of the inspection counts of this many most active monkeys
+    top: usize,
+}
+
+const PART_1: SimulationConfig = SimulationConfig {
+    rounds: 20,
+    relief: Relief::DivideBy(3),
+    top: 2,
+};
+
+const PART_2: SimulationConfig = SimulationConfig {
+    rounds: 10000,
+    relief: Relief::Modulo,
+    top: 2,
+};
 
 struct Monkey {
     items: VecDeque<u64>,
-    op: Box<dyn Fn(u64) -> u64>,
-    test: Box<dyn Fn(u64) -> usize>,
+    op: Operation,
+    // Items divisible by this go to the monkey `if_true`, the rest to `if_false`
+    divisor: u64,
+    if_true: usize,
+    if_false: usize,
     inspection_count: usize,
 }
 
-fn parse_op(expression: &str) -> Option<Box<impl Fn(u64) -> u64>> {
-    let func = evalexpr::build_operator_tree(expression).unwrap();
-    Some(Box::new(move |old: u64| -> u64 {
-        let context = context_map! {
-          "old" => i64::try_from(old).unwrap()
+impl Monkey {
+    fn target(&self, worry: u64) -> usize {
+        if worry.is_multiple_of(self.divisor) {
+            self.if_true
+        } else {
+            self.if_false
         }
-        .unwrap();
+    }
+}
 
-        func.eval_int_with_context(&context)
-            .unwrap()
-            .try_into()
-            .unwrap()
-    }))
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
 }
 
-fn parse_test(
-    (test_arg, target_1, target_2): (u64, u64, u64),
-) -> Option<Box<impl Fn(u64) -> usize>> {
-    Some(Box::new(move |x: u64| -> usize {
-        (if x % test_arg == 0 {
-            target_1
-        } else {
-            target_2
-        })
-        .try_into()
-        .unwrap()
-    }))
-}
-
-fn parse(input: &str) -> Vec<Monkey> {
-    let find_starting_items = Regex::new(r"Starting items: (.*)").unwrap();
-    let find_operation = Regex::new(r"Operation: new = (.*)").unwrap();
-    let find_test =
-        Regex::new(r"Test: divisible by (?P<test>\d+)\n.*(?P<target_1>\d+)\n.*(?P<target_2>\d+)")
-            .unwrap();
-
-    input
-        .split("\n\n")
-        .map(|chunk| Monkey {
-            items: find_starting_items
-                .captures(chunk)
-                .unwrap()
-                .get(1)
-                .unwrap()
-                .as_str()
-                .split(", ")
-                .map(|item| item.parse().unwrap())
-                .collect(),
-            op: parse_op(
-                find_operation
-                    .captures(chunk)
-                    .unwrap()
-                    .get(1)
-                    .unwrap()
-                    .as_str(),
-            )
-            .unwrap(),
-            test: parse_test(
-                find_test
-                    .captures_iter(chunk)
-                    .nth(0)
-                    .and_then(|capture_matches| {
-                        Some((
-                            (&capture_matches["test"]).parse::<u64>().ok()?,
-                            (&capture_matches["target_1"]).parse::<u64>().ok()?,
-                            (&capture_matches["target_2"]).parse::<u64>().ok()?,
-                        ))
-                    })
-                    .unwrap(),
+// Where every monkey's test gives the same answer for `worry` and `worry % modulus`, so worry
+// levels can be kept below it without changing where items are thrown
+fn worry_modulus(monkeys: &[Monkey]) -> u64 {
+    monkeys.iter().fold(1, |lcm, monkey| {
+        lcm / gcd(lcm, monkey.divisor) * monkey.divisor
+    })
+}
+
+impl Expr {
+    fn eval(&self, old: u64) -> Option<u64> {
+        match self {
+            Expr::Old => Some(old),
+            Expr::Num(n) => Some(*n),
+            Expr::Add(a, b) => a.eval(old)?.checked_add(b.eval(old)?),
+            Expr::Sub(a, b) => a.eval(old)?.checked_sub(b.eval(old)?),
+            Expr::Mul(a, b) => a.eval(old)?.checked_mul(b.eval(old)?),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Old => write!(f, "old"),
+            Expr::Num(n) => write!(f, "{}", n),
+            Expr::Add(a, b) => write!(f, "({} + {})", a, b),
+            Expr::Sub(a, b) => write!(f, "({} - {})", a, b),
+            Expr::Mul(a, b) => write!(f, "({} * {})", a, b),
+        }
+    }
+}
+
+impl Operation {
+    fn from_expr(expr: Expr) -> Self {
+        match expr {
+            Expr::Mul(a, b) if *a == Expr::Old && *b == Expr::Old => Operation::Square,
+            Expr::Add(a, b) => match (*a, *b) {
+                (Expr::Old, Expr::Num(k)) | (Expr::Num(k), Expr::Old) => Operation::Add(k),
+                (a, b) => Operation::Expr(Expr::Add(Box::new(a), Box::new(b))),
+            },
+            Expr::Mul(a, b) => match (*a, *b) {
+                (Expr::Old, Expr::Num(k)) | (Expr::Num(k), Expr::Old) => Operation::Mul(k),
+                (a, b) => Operation::Expr(Expr::Mul(Box::new(a), Box::new(b))),
+            },
+            expr => Operation::Expr(expr),
+        }
+    }
+
+    // Fails rather than wrapping around when the worry level doesn't fit
+    fn apply(&self, old: u64) -> Result<u64, String> {
+        match self {
+            Operation::Square => old.checked_mul(old),
+            Operation::Add(k) => old.checked_add(*k),
+            Operation::Mul(k) => old.checked_mul(*k),
+            Operation::Expr(expr) => expr.eval(old),
+        }
+        .ok_or_else(|| {
+            format!(
+                "Worry level overflowed computing {} with old = {}",
+                self, old
             )
-            .unwrap(),
-            inspection_count: 0,
         })
-        .collect()
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Square => write!(f, "old * old"),
+            Operation::Add(k) => write!(f, "old + {}", k),
+            Operation::Mul(k) => write!(f, "old * {}", k),
+            Operation::Expr(expr) => write!(f, "{}", expr),
+        }
+    }
+}
+
+// Recursive descent over `+`, `-`, `*` and parentheses, with the usual precedence
+fn parse_expr(tokens: &mut Peekable<CharIndices>, input: &str) -> Option<Expr> {
+    let mut expr = parse_term(tokens, input)?;
+    loop {
+        skip_spaces(tokens);
+        expr = match tokens.peek() {
+            Some((_, '+')) => {
+                tokens.next();
+                Expr::Add(Box::new(expr), Box::new(parse_term(tokens, input)?))
+            }
+            Some((_, '-')) => {
+                tokens.next();
+                Expr::Sub(Box::new(expr), Box::new(parse_term(tokens, input)?))
+            }
+            _ => return Some(expr),
+        }
+    }
+}
+
+fn parse_term(tokens: &mut Peekable<CharIndices>, input: &str) -> Option<Expr> {
+    let mut expr = parse_factor(tokens, input)?;
+    loop {
+        skip_spaces(tokens);
+        if tokens.next_if(|(_, c)| *c == '*').is_none() {
+            return Some(expr);
+        }
+        expr = Expr::Mul(Box::new(expr), Box::new(parse_factor(tokens, input)?));
+    }
+}
+
+fn parse_factor(tokens: &mut Peekable<CharIndices>, input: &str) -> Option<Expr> {
+    skip_spaces(tokens);
+    let (start, c) = tokens.next()?;
+    if c == '(' {
+        let expr = parse_expr(tokens, input)?;
+        skip_spaces(tokens);
+        return tokens.next_if(|(_, c)| *c == ')').map(|_| expr);
+    }
+    let mut end = start + c.len_utf8();
+    while let Some((i, c)) = tokens.next_if(|(_, c)| c.is_ascii_alphanumeric()) {
+        end = i + c.len_utf8();
+    }
+    match &input[start..end] {
+        "old" => Some(Expr::Old),
+        word => word.parse().ok().map(Expr::Num),
+    }
+}
+
+fn skip_spaces(tokens: &mut Peekable<CharIndices>) {
+    while tokens.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+}
+
+fn parse_op(expression: &str) -> Option<Operation> {
+    let mut tokens = expression.char_indices().peekable();
+    let expr = parse_expr(&mut tokens, expression)?;
+    skip_spaces(&mut tokens);
+    tokens.peek().is_none().then(|| Operation::from_expr(expr))
+}
+
+// Parses notes like the puzzle's, one monkey after another numbered from 0. Blank lines and
+// extra whitespace are ignored, and errors name the monkey and line they are on.
+fn parse(input: &str) -> Result<Vec<Monkey>, String> {
+    let mut lines = input
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.split_whitespace().join(" ")))
+        .filter(|(_, l)| !l.is_empty());
+    let mut monkeys = Vec::new();
+    // Where every monkey throws to, checked once all monkeys are known
+    let mut throws = Vec::new();
+    while let Some((line, header)) = lines.next() {
+        let id = header
+            .strip_prefix("Monkey ")
+            .and_then(|rest| rest.strip_suffix(':'))
+            .and_then(|id| id.parse::<usize>().ok())
+            .ok_or(format!("Line {}: expected \"Monkey N:\"", line))?;
+        if id != monkeys.len() {
+            return Err(format!(
+                "Monkey {}, line {}: expected monkey {} next",
+                id,
+                line,
+                monkeys.len()
+            ));
+        }
+        let error =
+            |line: usize, message: String| format!("Monkey {}, line {}: {}", id, line, message);
+        let mut field = |label: &str| match lines.next() {
+            Some((line, text)) => text
+                .strip_prefix(label)
+                .map(|value| (line, value.trim().to_string()))
+                .ok_or(error(line, format!("expected \"{}\"", label))),
+            None => Err(format!("Monkey {}: notes end before \"{}\"", id, label)),
+        };
+
+        let (line, items) = field("Starting items:")?;
+        let items = items
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                item.parse()
+                    .map_err(|_| error(line, format!("invalid item \"{}\"", item)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let (line, op) = field("Operation: new =")?;
+        let op = parse_op(&op).ok_or(error(line, format!("invalid operation \"{}\"", op)))?;
+
+        let (line, divisor) = field("Test: divisible by")?;
+        let divisor = divisor
+            .parse()
+            .ok()
+            .filter(|divisor| *divisor != 0)
+            .ok_or(error(line, format!("invalid divisor \"{}\"", divisor)))?;
+
+        let mut target = |label: &str| -> Result<usize, String> {
+            let (line, target) = field(label)?;
+            let target = target
+                .parse()
+                .map_err(|_| error(line, format!("invalid monkey \"{}\"", target)))?;
+            throws.push((id, line, target));
+            Ok(target)
+        };
+        let if_true = target("If true: throw to monkey")?;
+        let if_false = target("If false: throw to monkey")?;
+
+        monkeys.push(Monkey {
+            items,
+            op,
+            divisor,
+            if_true,
+            if_false,
+            inspection_count: 0,
+        });
+    }
+
+    if monkeys.is_empty() {
+        return Err("The notes don't mention any monkeys".to_string());
+    }
+    for (id, line, target) in throws {
+        // A monkey throwing to itself would keep inspecting the same item forever
+        if target == id || monkeys.len() <= target {
+            return Err(format!(
+                "Monkey {}, line {}: can't throw to monkey {}",
+                id, line, target
+            ));
+        }
+    }
+    Ok(monkeys)
 }
 
-fn play_round(monkeys: &mut Vec<Monkey>, reduced_worryness: bool) {
+fn parse_relief(input: &str) -> Option<Relief> {
+    match input.split_once(':') {
+        Some(("divide", k)) => Some(Relief::DivideBy(k.parse().ok().filter(|k| *k != 0)?)),
+        None if input == "modulo" => Some(Relief::Modulo),
+        None if input == "none" => Some(Relief::None),
+        _ => None,
+    }
+}
+
+fn play_round(monkeys: &mut [Monkey], relief: Relief) -> Result<(), String> {
+    let modulus = worry_modulus(monkeys);
     for i in 0..monkeys.len() {
         while let Some(mut item) = monkeys.get_mut(i).unwrap().items.pop_front() {
             let next_monkey_index = {
                 let monkey = monkeys.get_mut(i).unwrap();
-                let item_2 = item;
-                item = monkey.op.as_ref()(item);
-                if item < item_2 {
-                  panic!("Worryness can never decrease from operation!");
-                }
-                if reduced_worryness {
-                    item /= 3;
+                item = monkey.op.apply(item)?;
+                match relief {
+                    Relief::DivideBy(k) => item /= k,
+                    Relief::Modulo => item %= modulus,
+                    Relief::None => {}
                 }
                 monkey.inspection_count += 1;
-                monkey.test.as_ref()(item)
+                monkey.target(item)
             };
             monkeys
                 .get_mut(next_monkey_index)
@@ -110,51 +339,187 @@ fn play_round(monkeys: &mut Vec<Monkey>, reduced_worryness: bool) {
                 .push_back(item);
         }
     }
+    Ok(())
 }
 
-fn get_monkey_items(monkeys: &Vec<Monkey>) -> Vec<Vec<u64>> {
+fn get_monkey_items(monkeys: &[Monkey]) -> Vec<Vec<u64>> {
     monkeys
         .iter()
         .map(|m| m.items.iter().copied().collect_vec())
         .collect_vec()
 }
 
-fn get_monkey_business_after_20_rounds(input: &str) -> usize {
-    let mut monkeys = parse(input);
-    for _ in 0..20 {
-        play_round(&mut monkeys, true);
-    }
+fn get_inspection_counts(monkeys: &[Monkey]) -> Vec<usize> {
     monkeys
-        .into_iter()
+        .iter()
         .map(|monkey| monkey.inspection_count)
-        .sorted()
-        .rev()
-        .take(2)
-        .reduce(|accum, item| accum * item)
-        .unwrap()
+        .collect_vec()
 }
 
-fn get_monkey_business_after_10000_rounds(input: &str) -> usize {
-    let mut monkeys = parse(input);
-    for _ in 0..10000 {
-        play_round(&mut monkeys, false);
-    }
-    monkeys
+fn monkey_business(monkeys: &[Monkey], top: usize) -> usize {
+    get_inspection_counts(monkeys)
         .into_iter()
-        .map(|monkey| monkey.inspection_count)
         .sorted()
         .rev()
-        .take(2)
-        .reduce(|accum, item| accum * item)
-        .unwrap()
+        .take(top)
+        .product()
+}
+
+// Plays the configured number of rounds, showing the monkeys to `after_round` after each one
+fn simulate(
+    monkeys: &mut [Monkey],
+    config: &SimulationConfig,
+    mut after_round: impl FnMut(usize, &[Monkey]),
+) -> Result<usize, String> {
+    for round in 1..=config.rounds {
+        play_round(monkeys, config.relief)?;
+        after_round(round, monkeys);
+    }
+    Ok(monkey_business(monkeys, config.top))
+}
+
+// Follows a single item through one round, noting which monkeys inspect it. Items thrown to a
+// monkey later in the order are inspected again in the same round, others wait for the next one.
+fn play_item_round(
+    monkeys: &[Monkey],
+    (mut monkey, mut worry): (usize, u64),
+    modulus: u64,
+    inspected_by: &mut Vec<usize>,
+) -> Result<(usize, u64), String> {
+    loop {
+        inspected_by.push(monkey);
+        worry = monkeys[monkey].op.apply(worry)? % modulus;
+        let target = monkeys[monkey].target(worry);
+        if target <= monkey {
+            return Ok((target, worry));
+        }
+        monkey = target;
+    }
+}
+
+// How often each monkey inspects an item starting at `start` over the rounds. Items never
+// affect each other, and an item's (monkey, worry) state has finitely many values with modulo
+// relief, so it eventually repeats, after which the inspections repeat too.
+fn item_inspection_counts(
+    monkeys: &[Monkey],
+    start: (usize, u64),
+    modulus: u64,
+    rounds: u64,
+) -> Result<Vec<u64>, String> {
+    let mut counts = vec![0; monkeys.len()];
+    let mut seen = HashMap::new();
+    // The monkeys inspecting the item during each round so far
+    let mut history: Vec<Vec<usize>> = Vec::new();
+    let mut state = start;
+    while (history.len() as u64) < rounds {
+        if let Some(&cycle_start) = seen.get(&state) {
+            let cycle = &history[cycle_start..];
+            let remaining = rounds - history.len() as u64;
+            let (repeats, rest) = (
+                remaining / cycle.len() as u64,
+                remaining % cycle.len() as u64,
+            );
+            for (i, inspected_by) in cycle.iter().enumerate() {
+                let times = repeats + u64::from((i as u64) < rest);
+                for monkey in inspected_by {
+                    counts[*monkey] += times;
+                }
+            }
+            return Ok(counts);
+        }
+        seen.insert(state, history.len());
+
+        let mut inspected_by = Vec::new();
+        state = play_item_round(monkeys, state, modulus, &mut inspected_by)?;
+        for monkey in &inspected_by {
+            counts[*monkey] += 1;
+        }
+        history.push(inspected_by);
+    }
+    Ok(counts)
+}
+
+// The inspection counts after the rounds, without playing every one of them
+fn extrapolate_inspection_counts(monkeys: &[Monkey], rounds: u64) -> Result<Vec<u64>, String> {
+    let modulus = worry_modulus(monkeys);
+    let mut counts: Vec<u64> = monkeys
+        .iter()
+        .map(|monkey| monkey.inspection_count as u64)
+        .collect();
+    for (i, monkey) in monkeys.iter().enumerate() {
+        for item in &monkey.items {
+            let item_counts = item_inspection_counts(monkeys, (i, *item), modulus, rounds)?;
+            for (count, item_count) in counts.iter_mut().zip(item_counts) {
+                *count += item_count;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+fn extrapolate_monkey_business(
+    monkeys: &[Monkey],
+    config: &SimulationConfig,
+) -> Result<u128, String> {
+    if config.relief != Relief::Modulo {
+        return Err("Only worry levels with modulo relief are guaranteed to repeat".to_string());
+    }
+    Ok(
+        extrapolate_inspection_counts(monkeys, config.rounds as u64)?
+            .into_iter()
+            .sorted()
+            .rev()
+            .take(config.top)
+            .map(u128::from)
+            .product(),
+    )
+}
+
+fn get_monkey_business(input: &str, config: &SimulationConfig) -> Result<usize, String> {
+    simulate(&mut parse(input)?, config, |_, _| {})
 }
 
 fn main() {
     const INPUT: &str = include_str!("../inputs/11.txt");
     println!(
         "Monkey business after 20 days: {}",
-        get_monkey_business_after_20_rounds(INPUT)
+        get_monkey_business(INPUT, &PART_1).unwrap()
+    );
+    println!(
+        "Monkey business after 10000 days: {}",
+        get_monkey_business(INPUT, &PART_2).unwrap()
     );
+    let config = SimulationConfig {
+        rounds: 1_000_000_000_000,
+        ..PART_2
+    };
+    println!(
+        "Monkey business after {} days: {}",
+        config.rounds,
+        extrapolate_monkey_business(&parse(INPUT).unwrap(), &config).unwrap()
+    );
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let [rounds, relief, top] = &args[..] {
+        let config = SimulationConfig {
+            rounds: rounds.parse().unwrap(),
+            relief: parse_relief(relief).expect("Invalid relief"),
+            top: top.parse().unwrap(),
+        };
+        let mut monkeys = parse(INPUT).unwrap();
+        let business = simulate(&mut monkeys, &config, |round, monkeys| {
+            println!(
+                "After round {}: inspections {:?}, items {:?}",
+                round,
+                get_inspection_counts(monkeys),
+                get_monkey_items(monkeys)
+            );
+        });
+        match business {
+            Ok(business) => println!("Monkey business: {}", business),
+            Err(error) => println!("{}", error),
+        }
+    }
 }
 
 const EXAMPLE_INPUT: &str = "Monkey 0:
@@ -187,26 +552,53 @@ Monkey 3:
 
 #[test]
 fn test_parse() {
-    let parsed = parse(EXAMPLE_INPUT);
+    let parsed = parse(EXAMPLE_INPUT).unwrap();
     let first = parsed.first().unwrap();
     assert_eq!(first.items, vec![79, 98]);
-    assert_eq!(first.op.as_ref()(1), 19);
-    assert_eq!(first.op.as_ref()(2), 38);
-    assert_eq!(first.test.as_ref()(23), 2);
-    assert_eq!(first.test.as_ref()(24), 3);
+    assert_eq!(first.op, Operation::Mul(19));
+    assert_eq!(first.op.apply(2), Ok(38));
+    assert_eq!(first.divisor, 23);
+    assert_eq!(first.target(46), 2);
+    assert_eq!(first.target(24), 3);
 
     let second = parsed.get(1).unwrap();
     assert_eq!(second.items, vec![54, 65, 75, 74]);
-    assert_eq!(second.op.as_ref()(1), 7);
-    assert_eq!(second.op.as_ref()(2), 8);
-    assert_eq!(second.test.as_ref()(19), 2);
-    assert_eq!(second.test.as_ref()(20), 0);
+    assert_eq!(second.op, Operation::Add(6));
+    assert_eq!(second.op.apply(2), Ok(8));
+    assert_eq!(parsed[2].op, Operation::Square);
+    assert_eq!(second.target(19), 2);
+    assert_eq!(second.target(20), 0);
+    assert_eq!(worry_modulus(&parsed), 23 * 19 * 13 * 17);
+}
+
+#[test]
+fn test_parse_op() {
+    assert_eq!(parse_op("old*old"), Some(Operation::Square));
+    assert_eq!(parse_op("3 + old"), Some(Operation::Add(3)));
+    let op = parse_op("(old + 1) * (old - 2) + 4 * old").unwrap();
+    assert_eq!(op.to_string(), "(((old + 1) * (old - 2)) + (4 * old))");
+    assert_eq!(op.apply(5), Ok(38));
+    assert_eq!(
+        op.apply(1),
+        Err(
+            "Worry level overflowed computing (((old + 1) * (old - 2)) + (4 * old)) with old = 1"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        Operation::Square.apply(1 << 32),
+        Err("Worry level overflowed computing old * old with old = 4294967296".to_string())
+    );
+    assert_eq!(parse_op("old +"), None);
+    assert_eq!(parse_op("(old"), None);
+    assert_eq!(parse_op("old old"), None);
+    assert_eq!(parse_op("new * 2"), None);
 }
 
 #[test]
 fn example_1_round() {
-    let mut monkeys = parse(EXAMPLE_INPUT);
-    play_round(&mut monkeys, true);
+    let mut monkeys = parse(EXAMPLE_INPUT).unwrap();
+    play_round(&mut monkeys, Relief::DivideBy(3)).unwrap();
     assert_eq!(
         get_monkey_items(&monkeys),
         Vec::from([
@@ -220,9 +612,9 @@ fn example_1_round() {
 
 #[test]
 fn example_2_rounds() {
-    let mut monkeys = parse(EXAMPLE_INPUT);
-    play_round(&mut monkeys, true);
-    play_round(&mut monkeys, true);
+    let mut monkeys = parse(EXAMPLE_INPUT).unwrap();
+    play_round(&mut monkeys, Relief::DivideBy(3)).unwrap();
+    play_round(&mut monkeys, Relief::DivideBy(3)).unwrap();
     assert_eq!(
         get_monkey_items(&monkeys),
         Vec::from([
@@ -236,14 +628,14 @@ fn example_2_rounds() {
 
 #[test]
 fn example_monkey_business() {
-    assert_eq!(get_monkey_business_after_20_rounds(EXAMPLE_INPUT), 10605);
+    assert_eq!(get_monkey_business(EXAMPLE_INPUT, &PART_1), Ok(10605));
 }
 
 #[test]
 fn example_20_rounds_increased_worryness() {
-    let mut monkeys = parse(EXAMPLE_INPUT);
+    let mut monkeys = parse(EXAMPLE_INPUT).unwrap();
     for _ in 0..20 {
-        play_round(&mut monkeys, false);
+        play_round(&mut monkeys, Relief::Modulo).unwrap();
     }
     assert_eq!(
         monkeys
@@ -260,8 +652,142 @@ fn example_20_rounds_increased_worryness() {
 
 #[test]
 fn example_monkey_business_2() {
+    assert_eq!(get_monkey_business(EXAMPLE_INPUT, &PART_2), Ok(2713310158));
+}
+
+#[test]
+fn example_simulation_snapshots() {
+    let mut monkeys = parse(EXAMPLE_INPUT).unwrap();
+    let mut snapshots = Vec::new();
+    let config = SimulationConfig {
+        rounds: 20,
+        relief: Relief::Modulo,
+        top: 3,
+    };
+    let business = simulate(&mut monkeys, &config, |round, monkeys| {
+        if round == 1 || round == 20 {
+            snapshots.push((round, get_inspection_counts(monkeys)));
+        }
+    });
     assert_eq!(
-        get_monkey_business_after_10000_rounds(EXAMPLE_INPUT),
-        2713310158
+        snapshots,
+        vec![(1, vec![2, 4, 3, 6]), (20, vec![99, 97, 8, 103])]
     );
+    assert_eq!(business, Ok(103 * 99 * 97));
+
+    let config = SimulationConfig {
+        rounds: 20,
+        relief: Relief::None,
+        top: 2,
+    };
+    assert!(simulate(&mut parse(EXAMPLE_INPUT).unwrap(), &config, |_, _| {}).is_err());
+
+    assert_eq!(parse_relief("divide:3"), Some(Relief::DivideBy(3)));
+    assert_eq!(parse_relief("modulo"), Some(Relief::Modulo));
+    assert_eq!(parse_relief("none"), Some(Relief::None));
+    assert_eq!(parse_relief("divide"), None);
+    assert_eq!(parse_relief("divide:0"), None);
+}
+
+#[test]
+fn example_extrapolated_inspections() {
+    let monkeys = parse(EXAMPLE_INPUT).unwrap();
+    let mut simulated = parse(EXAMPLE_INPUT).unwrap();
+    let config = SimulationConfig {
+        rounds: 300,
+        ..PART_2
+    };
+    simulate(&mut simulated, &config, |round, simulated| {
+        assert_eq!(
+            extrapolate_inspection_counts(&monkeys, round as u64).unwrap(),
+            get_inspection_counts(simulated)
+                .into_iter()
+                .map(|count| count as u64)
+                .collect_vec(),
+            "round {}",
+            round
+        );
+    })
+    .unwrap();
+
+    assert_eq!(
+        extrapolate_monkey_business(&monkeys, &PART_2),
+        Ok(2713310158)
+    );
+    let config = SimulationConfig {
+        rounds: 1_000_000_000_000,
+        ..PART_2
+    };
+    let counts = extrapolate_inspection_counts(&monkeys, config.rounds as u64).unwrap();
+    // Every one of the 10 items is inspected at least once a round
+    assert!(counts.iter().sum::<u64>() >= 10 * config.rounds as u64);
+    assert!(extrapolate_monkey_business(&monkeys, &PART_1).is_err());
+}
+
+#[test]
+fn test_parse_notes() {
+    let notes = "Monkey 0:\r
+  Starting items:   1,2 ,  3\r
+  Operation:  new = old  * 19\r
+\tTest: divisible by 23\r
+    If true: throw to monkey 11\r
+    If false: throw to monkey 1\r
+\r
+\r
+Monkey 1:\r
+  Starting items:\r
+  Operation: new = old + 1\r
+  Test: divisible by 2\r
+    If true: throw to monkey 0\r
+    If false: throw to monkey 0\r
+";
+    let notes_with_12 = notes.to_string()
+        + &(2..=11)
+            .map(|id| {
+                format!(
+                    "Monkey {}:\nStarting items: 4\nOperation: new = old\nTest: divisible by 1\nIf true: throw to monkey 0\nIf false: throw to monkey 0\n",
+                    id
+                )
+            })
+            .join("\n");
+    let monkeys = parse(&notes_with_12).unwrap();
+    assert_eq!(monkeys.len(), 12);
+    assert_eq!(monkeys[0].items, vec![1, 2, 3]);
+    assert_eq!(monkeys[0].op, Operation::Mul(19));
+    assert_eq!((monkeys[0].if_true, monkeys[0].if_false), (11, 1));
+    assert!(monkeys[1].items.is_empty());
+
+    let errors = [
+        (notes, "Monkey 0, line 5: can't throw to monkey 11"),
+        ("", "The notes don't mention any monkeys"),
+        ("Monkey zero:", "Line 1: expected \"Monkey N:\""),
+        ("Monkey 1:", "Monkey 1, line 1: expected monkey 0 next"),
+        (
+            "Monkey 0:\n Starting items: 1",
+            "Monkey 0: notes end before \"Operation: new =\"",
+        ),
+        (
+            "Monkey 0:\nStarting items: 1, x",
+            "Monkey 0, line 2: invalid item \"x\"",
+        ),
+        (
+            "Monkey 0:\nItems: 1",
+            "Monkey 0, line 2: expected \"Starting items:\"",
+        ),
+        (
+            "Monkey 0:\nStarting items: 1\nOperation: new = old ** 2",
+            "Monkey 0, line 3: invalid operation \"old ** 2\"",
+        ),
+        (
+            &EXAMPLE_INPUT.replace("divisible by 13", "divisible by 0"),
+            "Monkey 2, line 18: invalid divisor \"0\"",
+        ),
+        (
+            &EXAMPLE_INPUT.replace("monkey 0\n", "monkey 1\n"),
+            "Monkey 1, line 13: can't throw to monkey 1",
+        ),
+    ];
+    for (notes, error) in errors {
+        assert_eq!(parse(notes).err(), Some(error.to_string()));
+    }
 }
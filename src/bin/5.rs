@@ -1,36 +1,124 @@
-use std::collections::HashMap;
-
 use itertools::Itertools;
+use pathfinding::prelude::bfs;
 
 type Command = (i32, i32, i32);
+type Stacks = Vec<Vec<String>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Crane {
+    CrateMover9000,
+    CrateMover9001,
+}
+
+enum Goal {
+    // The crate expected on top of each stack, or `None` for an empty one
+    Tops(Vec<Option<String>>),
+    Stacks(Stacks),
+}
 
-fn parse(input: &str) -> Option<(Vec<Vec<char>>, Vec<Command>)> {
+impl Goal {
+    // Tops written like the puzzle answer, one crate per stack, with a space for an empty stack
+    fn tops(tops: &str) -> Self {
+        Goal::Tops(
+            tops.chars()
+                .map(|c| (c != ' ').then(|| c.to_string()))
+                .collect(),
+        )
+    }
+
+    fn is_reached(&self, crates: &[Vec<String>]) -> bool {
+        match self {
+            Goal::Tops(tops) => crates
+                .iter()
+                .map(|q| q.last())
+                .eq(tops.iter().map(Option::as_ref)),
+            Goal::Stacks(stacks) => crates == stacks,
+        }
+    }
+}
+
+fn parse(input: &str) -> Option<(Stacks, Vec<Command>)> {
     input
         .split_once("\n\n")
         .map(|(first, second)| (parse_stacks(first), parse_commands(second)))
 }
 
-fn parse_stacks(input: &str) -> Vec<Vec<char>> {
-    let mut indexed_crates: HashMap<usize, Vec<char>> = HashMap::new();
-    for l in input.lines() {
-        for (i, c) in l.chars().enumerate() {
-            if let 'A'..='Z' = c {
-                if indexed_crates.contains_key(&i) {
-                    if let Some(q) = indexed_crates.get_mut(&i) {
-                        q.push(c);
+// Splits a line into its whitespace separated tokens, together with the character column range
+// each token occupies
+fn tokenize(line: &str) -> Vec<(usize, usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (column, (i, c)) in line.char_indices().chain([(line.len(), ' ')]).enumerate() {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some((column, i)),
+            (Some((column_start, i_start)), true) => {
+                tokens.push((column_start, column, &line[i_start..i]));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    tokens
+}
+
+fn parse_stacks(input: &str) -> Stacks {
+    let mut lines = input.lines().filter(|l| !l.trim().is_empty()).collect_vec();
+    let labels = lines.pop().map(tokenize).unwrap_or_default();
+    let mut stacks = vec![Vec::new(); labels.len()];
+
+    // Crates are assigned to the label that overlaps them, or else the closest label
+    for l in lines.into_iter().rev() {
+        for (start, end, token) in tokenize(l) {
+            if let Some(label) = token.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+                if let Some((i, _)) = labels.iter().enumerate().min_by_key(|(_, (a, b, _))| {
+                    if *a < end && start < *b {
+                        0
+                    } else {
+                        (a + b).abs_diff(start + end)
                     }
-                } else {
-                    indexed_crates.insert(i, vec![c]);
+                }) {
+                    stacks[i].push(label.to_string());
                 }
             }
         }
     }
+    stacks
+}
 
-    indexed_crates
-        .into_iter()
-        .sorted()
-        .map(|(_, q)| q.into_iter().rev().collect())
-        .collect()
+fn format_stacks(stacks: &[Vec<String>]) -> String {
+    let width = stacks
+        .iter()
+        .flatten()
+        .map(|label| label.chars().count() + 2)
+        .chain([stacks.len().to_string().len() + 2])
+        .max()
+        .unwrap();
+    let center = |text: String| {
+        let padding = width - text.chars().count();
+        format!(
+            "{}{}{}",
+            " ".repeat(padding / 2),
+            text,
+            " ".repeat(padding - padding / 2)
+        )
+    };
+
+    let height = stacks.iter().map(Vec::len).max().unwrap_or(0);
+    (0..height)
+        .rev()
+        .map(|j| {
+            stacks
+                .iter()
+                .map(|q| {
+                    q.get(j)
+                        .map(|label| format!("[{}]", label))
+                        .unwrap_or_default()
+                })
+                .map(center)
+                .join(" ")
+        })
+        .chain([(1..=stacks.len()).map(|i| center(i.to_string())).join(" ")])
+        .join("\n")
 }
 
 fn parse_commands(input: &str) -> Vec<Command> {
@@ -44,46 +132,138 @@ fn parse_commands(input: &str) -> Vec<Command> {
         .collect()
 }
 
-fn simulate(mut crates: Vec<Vec<char>>, commands: Vec<Command>) -> String {
-    for (mut count, a, b) in commands {
-      while 0 < count {
-        perform_move(&mut crates, (1, a, b));
-        count -= 1;
-      }
-    }
+fn format_commands(commands: &[Command]) -> String {
+    commands
+        .iter()
+        .map(|(count, a, b)| format!("move {} from {} to {}", count, a, b))
+        .join("\n")
+}
+
+fn simulate(crates: Stacks, commands: Vec<Command>) -> String {
+    top_crates(&run(crates, &commands, Crane::CrateMover9000))
+}
+
+fn simulate_2(crates: Stacks, commands: Vec<Command>) -> String {
+    top_crates(&run(crates, &commands, Crane::CrateMover9001))
+}
 
-    crates.iter().map(|q|q.last().unwrap()).collect()
+fn top_crates(crates: &[Vec<String>]) -> String {
+    crates.iter().map(|q| q.last().unwrap().as_str()).collect()
 }
 
-fn simulate_2(mut crates: Vec<Vec<char>>, commands: Vec<Command>) -> String {
-  for command in commands {
-      perform_move(&mut crates, command);
-  }
+fn run(mut crates: Stacks, commands: &[Command], crane: Crane) -> Stacks {
+    for command in commands {
+        perform_command(&mut crates, *command, crane);
+    }
+    crates
+}
 
-  crates.iter().map(|q|q.last().unwrap()).collect()
+fn perform_command(crates: &mut [Vec<String>], (mut count, a, b): Command, crane: Crane) {
+    match crane {
+        Crane::CrateMover9000 => {
+            while 0 < count {
+                perform_move(crates, (1, a, b));
+                count -= 1;
+            }
+        }
+        Crane::CrateMover9001 => perform_move(crates, (count, a, b)),
+    }
 }
 
-fn perform_move(crates: &mut Vec<Vec<char>>, (mut count, a, b): Command) {
-    let i_a: usize = (a-1).try_into().unwrap();
-    let i_b: usize = (b-1).try_into().unwrap();
+fn perform_move(crates: &mut [Vec<String>], (mut count, a, b): Command) {
+    let i_a: usize = (a - 1).try_into().unwrap();
+    let i_b: usize = (b - 1).try_into().unwrap();
     let mut tmp = Vec::new();
     while 0 < count {
-      if let Some(c) = crates.get_mut(i_a).unwrap().pop() {
-          tmp.push(c);
-      }
-      count -= 1;
+        if let Some(c) = crates.get_mut(i_a).unwrap().pop() {
+            tmp.push(c);
+        }
+        count -= 1;
     }
     tmp.reverse();
     crates.get_mut(i_b).unwrap().append(&mut tmp);
 }
 
+// Every command the crane can carry out on the stacks, along with the resulting stacks
+fn next_arrangements(crates: &[Vec<String>], crane: Crane) -> Vec<(Command, Stacks)> {
+    let stack_count = i32::try_from(crates.len()).unwrap();
+    (1..=stack_count)
+        .cartesian_product(1..=stack_count)
+        .filter(|(a, b)| a != b)
+        .flat_map(|(a, b)| {
+            let len = crates[usize::try_from(a - 1).unwrap()].len();
+            (1..=i32::try_from(len).unwrap()).map(move |count| (count, a, b))
+        })
+        .map(|command| {
+            let mut next = crates.to_vec();
+            perform_command(&mut next, command, crane);
+            (command, next)
+        })
+        .collect_vec()
+}
+
+// Breadth first search over every possible arrangement, so the first one reaching the goal uses
+// the fewest commands. Only feasible for small drawings, as the state space explodes quickly.
+fn find_shortest_commands(crates: Stacks, goal: &Goal, crane: Crane) -> Option<Vec<Command>> {
+    let path = bfs(
+        &crates,
+        |crates: &Stacks| {
+            next_arrangements(crates, crane)
+                .into_iter()
+                .map(|(_, next)| next)
+                .collect_vec()
+        },
+        |crates| goal.is_reached(crates),
+    )?;
+    // Work out which command led from each arrangement to the next
+    path.windows(2)
+        .map(|pair| {
+            next_arrangements(&pair[0], crane)
+                .into_iter()
+                .find(|(_, next)| *next == pair[1])
+                .map(|(command, _)| command)
+        })
+        .collect()
+}
+
 fn main() {
     const INPUT: &str = include_str!("../inputs/5.txt");
     let (crates, commands) = parse(INPUT).unwrap();
-    println!("The resulting crate configuration is: {}", simulate(crates, commands));
+    println!(
+        "The resulting crate configuration is: {}",
+        simulate(crates, commands)
+    );
 
     let (crates, commands) = parse(INPUT).unwrap();
-    println!("Instead using the CrateMover 9001, the crate configuration ends up as: {}", simulate_2(crates, commands));
+    println!(
+        "Instead using the CrateMover 9001, the crate configuration ends up as: {}",
+        simulate_2(crates.clone(), commands.clone())
+    );
+    match std::env::args().nth(1).as_deref() {
+        Some("stacks") => println!(
+            "{}",
+            format_stacks(&run(crates, &commands, Crane::CrateMover9001))
+        ),
+        Some("search") => {
+            let (crates, commands) = parse(EXAMPLE_INPUT).unwrap();
+            let goal = Goal::tops("CMZ");
+            println!(
+                "The quickest way to get CMZ on top in the example is:\n{}",
+                format_commands(
+                    &find_shortest_commands(crates.clone(), &goal, Crane::CrateMover9000).unwrap()
+                )
+            );
+
+            let goal = Goal::Stacks(run(crates.clone(), &commands, Crane::CrateMover9001));
+            println!(
+                "While the CrateMover 9001 can rearrange the example using only:\n{}",
+                format_commands(
+                    &find_shortest_commands(crates, &goal, Crane::CrateMover9001).unwrap()
+                )
+            );
+        }
+        _ => (),
+    }
 }
 
 const EXAMPLE_INPUT: &str = "
@@ -100,7 +280,11 @@ move 1 from 1 to 2";
 #[test]
 fn parse_test() {
     let expected = (
-        vec![vec!['Z', 'N'], vec!['M', 'C', 'D'], vec!['P']],
+        vec![
+            vec!["Z".to_string(), "N".to_string()],
+            vec!["M".to_string(), "C".to_string(), "D".to_string()],
+            vec!["P".to_string()],
+        ],
         vec![(1, 2, 1), (3, 1, 3), (2, 2, 1), (1, 1, 2)],
     );
 
@@ -109,11 +293,99 @@ fn parse_test() {
 
 #[test]
 fn example_1() {
-  let (crates, commands) = parse(EXAMPLE_INPUT).unwrap();
-  assert_eq!(simulate(crates, commands), "CMZ");
+    let (crates, commands) = parse(EXAMPLE_INPUT).unwrap();
+    assert_eq!(simulate(crates, commands), "CMZ");
 }
 
+#[test]
 fn example_2() {
-  let (crates, commands) = parse(EXAMPLE_INPUT).unwrap();
-  assert_eq!(simulate_2(crates, commands), "MCD");
-}
\ No newline at end of file
+    let (crates, commands) = parse(EXAMPLE_INPUT).unwrap();
+    assert_eq!(simulate_2(crates, commands), "MCD");
+}
+
+#[test]
+fn format_commands_test() {
+    let commands = vec![(1, 2, 1), (3, 1, 3), (2, 2, 1), (1, 1, 2)];
+    assert_eq!(parse_commands(&format_commands(&commands)), commands);
+}
+
+#[test]
+fn find_shortest_commands_test() {
+    let (crates, commands) = parse(EXAMPLE_INPUT).unwrap();
+    let target = run(crates.clone(), &commands, Crane::CrateMover9000);
+
+    let found =
+        find_shortest_commands(crates.clone(), &Goal::tops("CMZ"), Crane::CrateMover9000).unwrap();
+    assert!(found.len() <= commands.len());
+    assert_eq!(simulate(crates.clone(), found), "CMZ");
+
+    let found = find_shortest_commands(
+        crates.clone(),
+        &Goal::Stacks(target.clone()),
+        Crane::CrateMover9000,
+    )
+    .unwrap();
+    assert!(found.len() <= commands.len());
+    let found = parse_commands(&format_commands(&found));
+    assert_eq!(run(crates.clone(), &found, Crane::CrateMover9000), target);
+
+    let found =
+        find_shortest_commands(crates.clone(), &Goal::tops("MCD"), Crane::CrateMover9001).unwrap();
+    assert_eq!(simulate_2(crates.clone(), found), "MCD");
+
+    // Every stack needs the given top, so stacks can't be emptied to make up the answer
+    assert_eq!(
+        find_shortest_commands(crates.clone(), &Goal::tops("CD"), Crane::CrateMover9000),
+        None
+    );
+    let found =
+        find_shortest_commands(crates.clone(), &Goal::tops("C D"), Crane::CrateMover9000).unwrap();
+    let tops = run(crates.clone(), &found, Crane::CrateMover9000)
+        .iter()
+        .map(|q| q.last().cloned())
+        .collect_vec();
+    assert_eq!(
+        tops,
+        vec![Some("C".to_string()), None, Some("D".to_string())]
+    );
+
+    // Reaching the arrangement the search starts from takes no commands at all
+    assert_eq!(
+        find_shortest_commands(crates.clone(), &Goal::Stacks(crates), Crane::CrateMover9000),
+        Some(vec![])
+    );
+}
+
+#[test]
+fn parse_wide_stacks_test() {
+    let drawing = "
+                                                    [x]
+[A] [b] [3]                     [J] [K] [L] [M] [N] [O]
+ 1   2   3   4   5   6   7   8   9   10  11  12  13  14 ";
+    let stacks = parse_stacks(drawing);
+    assert_eq!(stacks.len(), 14);
+    assert_eq!(stacks[1], vec!["b".to_string()]);
+    assert_eq!(stacks[2], vec!["3".to_string()]);
+    assert!(stacks[3].is_empty());
+    assert_eq!(stacks[13], vec!["O".to_string(), "x".to_string()]);
+    assert_eq!(parse_stacks(&format_stacks(&stacks)), stacks);
+
+    let stacks = parse_stacks("[Foo]\n[Ba] [Q]\n  1    2");
+    assert_eq!(
+        stacks,
+        vec![
+            vec!["Ba".to_string(), "Foo".to_string()],
+            vec!["Q".to_string()]
+        ]
+    );
+    assert_eq!(parse_stacks(&format_stacks(&stacks)), stacks);
+}
+
+#[test]
+fn format_stacks_test() {
+    let (drawing, _) = EXAMPLE_INPUT.split_once("\n\n").unwrap();
+    assert_eq!(
+        format_stacks(&parse_stacks(drawing)),
+        drawing.trim_start_matches('\n')
+    );
+}
@@ -1,11 +1,104 @@
-use itertools::Itertools;
+use std::{
+    io::{self, BufReader, Read},
+    ops::RangeInclusive,
+};
+
+#[derive(Debug, PartialEq)]
+struct MarkerReport {
+    window_size: usize,
+    // Every position where the last `window_size` characters are all different
+    markers: Vec<usize>,
+    // Positions where the previous position was a marker, but this one isn't
+    breaks: Vec<usize>,
+}
+
+// Single pass over the stream, keeping count of how many times each byte occurs in the current
+// window. The window is all unique whenever the number of distinct bytes equals its size.
+fn find_marker<R: Read>(reader: R, window_size: usize) -> io::Result<Option<usize>> {
+    if window_size == 0 {
+        return Ok(Some(0));
+    }
+    let mut counts = [0usize; 256];
+    let mut window = vec![0u8; window_size];
+    let mut distinct = 0;
+    for (i, byte) in BufReader::new(reader).bytes().enumerate() {
+        let byte = byte?;
+        if window_size <= i {
+            let oldest = &mut counts[usize::from(window[i % window_size])];
+            *oldest -= 1;
+            if *oldest == 0 {
+                distinct -= 1;
+            }
+        }
+
+        let newest = &mut counts[usize::from(byte)];
+        if *newest == 0 {
+            distinct += 1;
+        }
+        *newest += 1;
+        window[i % window_size] = byte;
+
+        if distinct == window_size {
+            return Ok(Some(i + 1));
+        }
+    }
+    Ok(None)
+}
 
 fn find_start_marker<const N: usize>(input: &str) -> Option<usize> {
-    input.chars().collect_vec()[..]
-        .windows(N)
+    find_marker(input.as_bytes(), N).ok()?
+}
+
+// For every position, the number of characters leading up to and including it that are all
+// different. Any window shorter than or equal to this length ending there is a marker.
+fn distinct_run_lengths(input: &str) -> Vec<usize> {
+    let mut last_seen = [None; 256];
+    let mut run_start = 0;
+    input
+        .bytes()
         .enumerate()
-        .find(|(i, pattern)| pattern.into_iter().unique().count() == N)
-        .map(|(i, _)| i + N)
+        .map(|(i, byte)| {
+            if let Some(previous) = last_seen[usize::from(byte)] {
+                run_start = run_start.max(previous + 1);
+            }
+            last_seen[usize::from(byte)] = Some(i);
+            i + 1 - run_start
+        })
+        .collect()
+}
+
+// Returns the start and length of the longest run of distinct characters
+fn find_longest_distinct_run(input: &str) -> Option<(usize, usize)> {
+    distinct_run_lengths(input)
+        .into_iter()
+        .enumerate()
+        .rev()
+        .max_by_key(|(_, len)| *len)
+        .map(|(i, len)| (i + 1 - len, len))
+}
+
+fn analyse_markers(input: &str, window_sizes: RangeInclusive<usize>) -> Vec<MarkerReport> {
+    let run_lengths = distinct_run_lengths(input);
+    window_sizes
+        .map(|window_size| {
+            let mut report = MarkerReport {
+                window_size,
+                markers: Vec::new(),
+                breaks: Vec::new(),
+            };
+            let mut was_marker = false;
+            for (i, len) in run_lengths.iter().enumerate() {
+                let is_marker = window_size <= *len;
+                if is_marker {
+                    report.markers.push(i + 1);
+                } else if was_marker {
+                    report.breaks.push(i + 1);
+                }
+                was_marker = is_marker;
+            }
+            report
+        })
+        .collect()
 }
 
 const find_start_of_packet: fn(&str) -> Option<usize> = find_start_marker::<4>;
@@ -22,6 +115,31 @@ fn main() {
         "The start-of-message marker is at position {}",
         find_start_of_message(INPUT).unwrap()
     );
+
+    let (start, len) = find_longest_distinct_run(INPUT).unwrap();
+    println!(
+        "The longest run of distinct characters is {} long, starting at {}",
+        len, start
+    );
+    for report in analyse_markers(INPUT, 4..=14) {
+        println!(
+            "Window size {:2}: {:4} markers, distinctness broken {} times",
+            report.window_size,
+            report.markers.len(),
+            report.breaks.len()
+        );
+    }
+
+    if let Some(window_size) = std::env::args().nth(1).and_then(|arg| arg.parse().ok()) {
+        match find_marker(io::stdin(), window_size) {
+            Ok(Some(position)) => println!(
+                "A marker of size {} in stdin is at position {}",
+                window_size, position
+            ),
+            Ok(None) => println!("Found no marker of size {} in stdin", window_size),
+            Err(error) => println!("Failed to read stdin: {}", error),
+        }
+    }
 }
 
 const EXAMPLE_INPUTS: [&str; 5] = [
@@ -50,3 +168,61 @@ fn example_find_start_of_messages() {
         );
     }
 }
+
+#[test]
+fn test_find_marker() {
+    let input = EXAMPLE_INPUTS[0].as_bytes();
+    assert_eq!(find_marker(input, 1).unwrap(), Some(1));
+    assert_eq!(find_marker(input, 4).unwrap(), Some(7));
+    assert_eq!(find_marker(input, 14).unwrap(), Some(19));
+    assert_eq!(find_marker(input, 27).unwrap(), None);
+    assert_eq!(find_marker("aaaa".as_bytes(), 2).unwrap(), None);
+    assert_eq!(find_marker("".as_bytes(), 4).unwrap(), None);
+
+    let long_input = "ab".repeat(100_000) + "cdef";
+    assert_eq!(
+        find_marker(long_input.as_bytes(), 5).unwrap(),
+        Some(long_input.len() - 1)
+    );
+}
+
+#[test]
+fn test_analyse_markers() {
+    let reports = analyse_markers("abcabba", 2..=3);
+    assert_eq!(
+        reports,
+        vec![
+            MarkerReport {
+                window_size: 2,
+                markers: vec![2, 3, 4, 5, 7],
+                breaks: vec![6],
+            },
+            MarkerReport {
+                window_size: 3,
+                markers: vec![3, 4, 5],
+                breaks: vec![6],
+            },
+        ]
+    );
+
+    for (i, input) in EXAMPLE_INPUTS.iter().enumerate() {
+        let reports = analyse_markers(input, 4..=14);
+        assert_eq!(
+            reports.first().unwrap().markers.first().copied(),
+            find_start_of_packet(input)
+        );
+        assert_eq!(
+            reports.last().unwrap().markers.first().copied(),
+            find_start_of_message(input),
+            "example {}",
+            i
+        );
+    }
+}
+
+#[test]
+fn test_find_longest_distinct_run() {
+    assert_eq!(find_longest_distinct_run("abcabba"), Some((0, 3)));
+    assert_eq!(find_longest_distinct_run("aabcdd"), Some((1, 4)));
+    assert_eq!(find_longest_distinct_run(""), None);
+}
@@ -1,107 +1,603 @@
+use std::{collections::HashMap, rc::Rc};
+
 use itertools::Itertools;
 
 #[derive(PartialEq, Debug)]
 enum Object {
     File(usize),
-    Dir(Vec<Box<Object>>),
+    Dir(Vec<usize>),
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: Option<usize>,
+    object: Object,
+    // Total size of the node, including everything below it for directories. Directory sizes are
+    // only filled in by `compute_sizes`.
+    size: usize,
 }
 
+// All nodes are stored in a single list and refer to each other by index, with the root
+// directory always being the first node
+struct FileSystem {
+    nodes: Vec<Node>,
+}
+
+impl FileSystem {
+    const ROOT: usize = 0;
+
+    fn new() -> Self {
+        FileSystem {
+            nodes: vec![Node {
+                name: String::new(),
+                parent: None,
+                object: Object::Dir(Vec::new()),
+                size: 0,
+            }],
+        }
+    }
+
+    fn add(&mut self, parent: usize, name: &str, object: Object) -> usize {
+        let id = self.nodes.len();
+        let size = match object {
+            Object::File(s) => s,
+            Object::Dir(_) => 0,
+        };
+        self.nodes.push(Node {
+            name: name.to_string(),
+            parent: Some(parent),
+            object,
+            size,
+        });
+        if let Object::Dir(children) = &mut self.nodes[parent].object {
+            children.push(id);
+        }
+        id
+    }
+
+    fn resize_file(&mut self, id: usize, size: usize) {
+        if let Object::File(_) = self.nodes[id].object {
+            self.nodes[id].object = Object::File(size);
+            self.nodes[id].size = size;
+        }
+    }
+
+    // Single post-order pass: nodes are always added after their parent, so going backwards
+    // through the list finishes every directory before its parent is reached
+    fn compute_sizes(&mut self) {
+        for node in self.nodes.iter_mut() {
+            if let Object::Dir(_) = node.object {
+                node.size = 0;
+            }
+        }
+        for id in (0..self.nodes.len()).rev() {
+            if let Some(parent) = self.nodes[id].parent {
+                self.nodes[parent].size += self.nodes[id].size;
+            }
+        }
+    }
+
+    fn children(&self, id: usize) -> &[usize] {
+        match &self.nodes[id].object {
+            Object::Dir(children) => children,
+            Object::File(_) => &[],
+        }
+    }
+
+    fn child(&self, id: usize, name: &str) -> Option<usize> {
+        self.children(id)
+            .iter()
+            .copied()
+            .find(|child| self.nodes[*child].name == name)
+    }
+
+    fn is_dir(&self, id: usize) -> bool {
+        matches!(self.nodes[id].object, Object::Dir(_))
+    }
+
+    fn size(&self, id: usize) -> usize {
+        self.nodes[id].size
+    }
+
+    fn path(&self, id: usize) -> String {
+        let mut names = Vec::new();
+        let mut current = id;
+        while let Some(parent) = self.nodes[current].parent {
+            names.push(self.nodes[current].name.as_str());
+            current = parent;
+        }
+        if names.is_empty() {
+            return "/".to_string();
+        }
+        names
+            .iter()
+            .rev()
+            .map(|name| format!("/{}", name))
+            .collect()
+    }
+
+    fn lookup(&self, path: &str) -> Option<usize> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(Self::ROOT, |dir, name| self.child(dir, name))
+    }
+
+    fn directories(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(|id| self.is_dir(*id))
+    }
+}
+
+#[derive(Debug, PartialEq)]
 enum Command<'a> {
     List,
-    Navigate(&'a [&'a str]),
+    Navigate(&'a str),
+}
+
+#[derive(Debug, PartialEq)]
+enum Inconsistency {
+    UnknownLine {
+        line: usize,
+    },
+    OutputWithoutListing {
+        line: usize,
+    },
+    AboveRoot {
+        line: usize,
+    },
+    NotADirectory {
+        line: usize,
+        path: String,
+    },
+    UnlistedDirectory {
+        line: usize,
+        path: String,
+    },
+    KindChanged {
+        line: usize,
+        path: String,
+    },
+    SizeChanged {
+        line: usize,
+        path: String,
+        old: usize,
+        new: usize,
+    },
 }
 
-fn parse_tree(input: &str) -> Option<Object> {
-    parse_sub_tree(&mut input.lines())
+fn parse_command(line: &str) -> Option<Command<'_>> {
+    match line.split(' ').collect_vec()[..] {
+        ["$", "cd", path] => Some(Command::Navigate(path)),
+        ["$", "ls"] => Some(Command::List),
+        _ => None,
+    }
+}
+
+fn parse_output(line: &str) -> Option<(&str, Object)> {
+    match line.split(' ').collect_vec()[..] {
+        ["dir", name] => Some((name, Object::Dir(Vec::new()))),
+        [size, name] => Some((name, Object::File(size.parse().ok()?))),
+        _ => None,
+    }
 }
 
-fn parse_sub_tree(input: &mut std::str::Lines) -> Option<Object> {
-    // Continue consuming lines
-    while let Some(line) = input.next() {
-        // explicitly stop on ".." - done parsing subtree
-        if line.starts_with("$ cd ..") {
-            return None;
-        } else if line.starts_with("$ cd") {
-            let mut dir = Vec::new();
-            while let Some(object) = parse_sub_tree(input) {
-                dir.push(Box::new(object));
+// Replays the transcript like a shell would, keeping track of the current working directory.
+// Directories can be visited and listed any number of times, and anything that doesn't add up
+// is reported alongside the reconstructed filesystem.
+fn interpret_transcript(input: &str) -> (FileSystem, Vec<Inconsistency>) {
+    let mut fs = FileSystem::new();
+    let mut inconsistencies = Vec::new();
+    let mut cwd = FileSystem::ROOT;
+    let mut listing = false;
+    for (line, text) in (1..).zip(input.lines()) {
+        if let Some(command) = parse_command(text) {
+            listing = command == Command::List;
+            if let Command::Navigate(path) = command {
+                if path.starts_with('/') {
+                    cwd = FileSystem::ROOT;
+                }
+                for name in path.split('/').filter(|name| !name.is_empty()) {
+                    match name {
+                        "." => (),
+                        ".." => match fs.nodes[cwd].parent {
+                            Some(parent) => cwd = parent,
+                            None => inconsistencies.push(Inconsistency::AboveRoot { line }),
+                        },
+                        _ => match fs.child(cwd, name) {
+                            Some(dir) if fs.is_dir(dir) => cwd = dir,
+                            Some(file) => {
+                                inconsistencies.push(Inconsistency::NotADirectory {
+                                    line,
+                                    path: fs.path(file),
+                                });
+                                break;
+                            }
+                            None => {
+                                cwd = fs.add(cwd, name, Object::Dir(Vec::new()));
+                                inconsistencies.push(Inconsistency::UnlistedDirectory {
+                                    line,
+                                    path: fs.path(cwd),
+                                });
+                            }
+                        },
+                    }
+                }
             }
-            return Some(Object::Dir(dir));
-        } else if line.chars().nth(0).map(char::is_numeric).unwrap_or(false) {
-            return Some(Object::File(line.split(' ').next()?.parse().ok()?));
+        } else if let Some((name, object)) = parse_output(text) {
+            if !listing {
+                inconsistencies.push(Inconsistency::OutputWithoutListing { line });
+            }
+            match (fs.child(cwd, name), object) {
+                (None, object) => {
+                    fs.add(cwd, name, object);
+                }
+                (Some(id), Object::File(new)) => match fs.nodes[id].object {
+                    Object::File(old) if old != new => {
+                        fs.resize_file(id, new);
+                        inconsistencies.push(Inconsistency::SizeChanged {
+                            line,
+                            path: fs.path(id),
+                            old,
+                            new,
+                        });
+                    }
+                    Object::File(_) => (),
+                    Object::Dir(_) => inconsistencies.push(Inconsistency::KindChanged {
+                        line,
+                        path: fs.path(id),
+                    }),
+                },
+                (Some(id), Object::Dir(_)) => {
+                    if !fs.is_dir(id) {
+                        inconsistencies.push(Inconsistency::KindChanged {
+                            line,
+                            path: fs.path(id),
+                        });
+                    }
+                }
+            }
+        } else {
+            inconsistencies.push(Inconsistency::UnknownLine { line });
         }
     }
-    None
+    fs.compute_sizes();
+    (fs, inconsistencies)
 }
 
-fn parse_command<'a>(line: &'a [&'a str]) -> Option<Command<'a>> {
-    match &line[..4] {
-        ["$ cd"] => Some(Command::Navigate(&line[4..])),
-        ["$ ls"] => Some(Command::List),
-        _ => None,
+fn parse_tree(input: &str) -> Result<FileSystem, Vec<Inconsistency>> {
+    match interpret_transcript(input) {
+        (fs, inconsistencies) if inconsistencies.is_empty() => Ok(fs),
+        (_, inconsistencies) => Err(inconsistencies),
     }
 }
 
-fn object_size(tree: &Object) -> usize {
-    match tree {
-        Object::File(s) => *s,
-        Object::Dir(d) => d.iter().map(|b| object_size(b.as_ref())).sum(),
+fn format_size(size: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut scaled = size as f64;
+    let mut unit = 0;
+    while 1024.0 <= scaled && unit + 1 < UNITS.len() {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", size, UNITS[unit])
+    } else {
+        format!("{:.1}{}", scaled, UNITS[unit])
     }
 }
 
-fn find_sum_of_max_size_directories(tree: &Object, max_size: usize) -> usize {
-    let rest = if let Object::Dir(d) = tree {
-        d.iter()
-            .map(|b| match b.as_ref() {
-                // Only count if sub-object is a directory (pretty messy. I know)
-                Object::Dir(_) => find_sum_of_max_size_directories(b.as_ref(), max_size),
-                _ => 0,
-            })
-            .sum()
+fn format_tree(fs: &FileSystem) -> String {
+    let mut lines = vec![format!("/ [{}]", format_size(fs.size(FileSystem::ROOT)))];
+    // Walk depth first, keeping track of the line prefix the children of each node are drawn with
+    let mut stack = fs
+        .children(FileSystem::ROOT)
+        .iter()
+        .sorted_by_key(|child| &fs.nodes[**child].name)
+        .rev()
+        .enumerate()
+        .map(|(i, child)| (*child, String::new(), i == 0))
+        .collect_vec();
+    while let Some((id, prefix, is_last)) = stack.pop() {
+        lines.push(format!(
+            "{}{} {} [{}]",
+            prefix,
+            if is_last { "└──" } else { "├──" },
+            fs.nodes[id].name,
+            format_size(fs.size(id))
+        ));
+        let prefix = prefix + if is_last { "    " } else { "│   " };
+        for (i, child) in fs
+            .children(id)
+            .iter()
+            .sorted_by_key(|child| &fs.nodes[**child].name)
+            .rev()
+            .enumerate()
+        {
+            stack.push((*child, prefix.clone(), i == 0));
+        }
+    }
+    lines.join("\n")
+}
+
+// Every directory sorted from largest to smallest, like `du -h | sort -rh`
+fn format_du(fs: &FileSystem) -> String {
+    fs.directories()
+        .sorted_by_key(|dir| std::cmp::Reverse(fs.size(*dir)))
+        .map(|dir| format!("{}\t{}", format_size(fs.size(dir)), fs.path(dir)))
+        .join("\n")
+}
+
+fn find_largest(fs: &FileSystem, directories: bool, count: usize) -> Vec<usize> {
+    (0..fs.nodes.len())
+        .filter(|id| fs.is_dir(*id) == directories)
+        .sorted_by_key(|id| std::cmp::Reverse(fs.size(*id)))
+        .take(count)
+        .collect()
+}
+
+fn find_sum_of_max_size_directories(fs: &FileSystem, max_size: usize) -> usize {
+    fs.directories()
+        .map(|dir| fs.size(dir))
+        .filter(|size| *size <= max_size)
+        .sum()
+}
+
+fn find_smallest_directory_to_delete_for_space(fs: &FileSystem, to_free: usize) -> Option<usize> {
+    fs.directories()
+        .filter(|dir| to_free <= fs.size(*dir))
+        .min_by_key(|dir| fs.size(*dir))
+}
+
+fn space_to_free(fs: &FileSystem, disk_size: usize, required: usize) -> usize {
+    let currently_free = disk_size.saturating_sub(fs.size(FileSystem::ROOT));
+    required.saturating_sub(currently_free)
+}
+
+fn find_smallest_eligible_directory_to_delete(
+    fs: &FileSystem,
+    disk_size: usize,
+    required: usize,
+) -> Option<usize> {
+    find_smallest_directory_to_delete_for_space(fs, space_to_free(fs, disk_size, required))
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
     } else {
-        0
-    };
+        gcd(b, a % b)
+    }
+}
+
+// The amounts that deleting some set of directories adds up to, as a bitset up to a limit, along
+// with the largest of them
+#[derive(Clone)]
+struct Amounts {
+    bits: Vec<u64>,
+    max: usize,
+}
+
+impl Amounts {
+    fn new(limit: usize) -> Self {
+        let mut bits = vec![0; limit / 64 + 1];
+        bits[0] = 1;
+        Amounts { bits, max: 0 }
+    }
+
+    fn contains(&self, amount: usize) -> bool {
+        amount <= self.max && self.bits[amount / 64] >> (amount % 64) & 1 == 1
+    }
+
+    // Adds every amount of `other` plus `shift`, dropping everything past the limit. Only the
+    // words of `other` up to its largest amount are looked at.
+    fn add_shifted(&mut self, other: &Amounts, shift: usize) {
+        let limit = self.bits.len() * 64 - 1;
+        if limit < shift {
+            return;
+        }
+        let (words, offset) = (shift / 64, shift % 64);
+        let target = &mut self.bits[words..];
+        let source = &other.bits[..=(other.max / 64).min(target.len() - 1)];
+        for (current, word) in target.iter_mut().zip(source) {
+            *current |= word << offset;
+        }
+        if 0 < offset {
+            for (current, word) in target[1..].iter_mut().zip(source) {
+                *current |= word >> (64 - offset);
+            }
+        }
+        self.max = self.max.max((other.max + shift).min(limit));
+    }
+
+    // Word `i` of the bitset once `shift` has been added to every amount
+    fn shifted_word(&self, i: usize, shift: usize) -> u64 {
+        let (words, offset) = (shift / 64, shift % 64);
+        let Some(j) = i.checked_sub(words) else {
+            return 0;
+        };
+        let word = |j: usize| self.bits.get(j).copied().unwrap_or(0);
+        if 0 < offset && 0 < j {
+            word(j) << offset | word(j - 1) >> (64 - offset)
+        } else {
+            word(j) << offset
+        }
+    }
+}
+
+// How many directories there are in each directory's subtree, counting itself, given the
+// directories in depth first order
+fn subtree_dir_counts(fs: &FileSystem, order: &[usize]) -> Vec<usize> {
+    let mut dir_count = vec![1; fs.nodes.len()];
+    for dir in order.iter().rev() {
+        if let Some(parent) = fs.nodes[*dir].parent {
+            dir_count[parent] += dir_count[*dir];
+        }
+    }
+    dir_count
+}
 
-    let curr_size = object_size(tree);
-    (if curr_size <= max_size { curr_size } else { 0 }) + rest
-}
-
-fn find_smallest_directory_to_delete_for_space(tree: &Object, to_free: usize) -> Option<usize> {
-    match tree {
-        Object::Dir(d) => {
-            let rest = d
-                .iter()
-                .filter_map(|b| find_smallest_directory_to_delete_for_space(b.as_ref(), to_free))
-                .min();
-            let current_size = object_size(tree);
-            if to_free <= current_size {
-                Some(rest.unwrap_or(current_size).min(current_size))
-            } else {
-                rest
+// Goes through the directories in depth first order, keeping track of which amounts deleting some
+// of them adds up to, up to `limit`. Deleting a directory skips past all of its subdirectories, so
+// its size is added to the amounts from before it once its subtree is done. `added` is called for
+// each directory just before that happens, along with those earlier amounts and the amounts so
+// far, and stops the search by returning true.
+//
+// Directories waiting for their subtree share the amounts from before them until something changes
+// them, so a chain of directories that are each visited first only holds on to a single copy.
+fn deletion_knapsack(
+    fs: &FileSystem,
+    order: &[usize],
+    unit: usize,
+    limit: usize,
+    mut added: impl FnMut(usize, &Amounts, &Amounts) -> bool,
+) -> Amounts {
+    let dir_count = subtree_dir_counts(fs, order);
+    let mut reachable = Rc::new(Amounts::new(limit));
+    let mut pending: HashMap<usize, Vec<(usize, Rc<Amounts>)>> = HashMap::new();
+    'search: for position in 0..=order.len() {
+        for (dir, before) in pending.remove(&position).unwrap_or_default() {
+            if added(dir, &before, &reachable) {
+                break 'search;
+            }
+            Rc::make_mut(&mut reachable).add_shifted(&before, fs.size(dir) / unit);
+        }
+
+        if let Some(dir) = order.get(position).copied() {
+            if fs.size(dir) / unit <= limit {
+                pending
+                    .entry(position + dir_count[dir])
+                    .or_default()
+                    .push((dir, Rc::clone(&reachable)));
             }
         }
-        _ => None,
     }
+    Rc::try_unwrap(reachable).unwrap_or_else(|shared| (*shared).clone())
 }
 
-fn find_smallest_eligible_directory_to_delete(tree: &Object) -> Option<usize> {
-    let currently_free = 70000000 - object_size(tree);
-    find_smallest_directory_to_delete_for_space(tree, 30000000 - currently_free)
+// Finds the set of directories that frees up at least `to_free` while deleting as little as
+// possible. A directory and one of its subdirectories are never both picked, as that would count
+// the subdirectory twice.
+//
+// Works like a knapsack over the directories, only going up to the size of the best single
+// directory, since no plan deleting more is worth it. Sizes are counted in units of their greatest
+// common divisor to keep the bitsets small, and the subdirectory with the most directories below
+// it is visited first, so only about log2(directories) copies of the amounts are held at once.
+fn plan_deletion(fs: &FileSystem, to_free: usize) -> Option<Vec<usize>> {
+    if to_free == 0 {
+        return Some(Vec::new());
+    }
+    let best_single = find_smallest_directory_to_delete_for_space(fs, to_free)?;
+
+    let depth_first = |dir_count: &[usize]| {
+        let mut order = Vec::new();
+        let mut stack = vec![FileSystem::ROOT];
+        while let Some(dir) = stack.pop() {
+            order.push(dir);
+            stack.extend(
+                fs.children(dir)
+                    .iter()
+                    .filter(|child| fs.is_dir(**child))
+                    .sorted_by_key(|child| dir_count[**child]),
+            );
+        }
+        order
+    };
+    let order = depth_first(&subtree_dir_counts(
+        fs,
+        &depth_first(&vec![0; fs.nodes.len()]),
+    ));
+    let unit = order
+        .iter()
+        .fold(0, |unit, dir| gcd(unit, fs.size(*dir)))
+        .max(1);
+    let limit = fs.size(best_single) / unit;
+
+    let reachable = deletion_knapsack(fs, &order, unit, limit, |_, _, _| false);
+    let mut freed = (to_free.div_ceil(unit)..=limit).find(|freed| reachable.contains(*freed))?;
+    // The plan is made up of the directory that first reached the amount, along with a plan for
+    // the rest, which was reachable before that directory so is made up of directories before and
+    // outside of it, and was reached earlier. Rather than remembering this for every amount, search
+    // again, remembering it for a window of amounts just below what is left. With windows an
+    // eighth of the limit, this takes at most 8 more searches and 4 bitsets' worth of memory.
+    let window = limit / 8 + 1;
+    let mut plan = Vec::new();
+    while 0 < freed {
+        let low = freed.saturating_sub(window - 1).max(1);
+        let mut first = vec![u32::MAX; freed - low + 1];
+        deletion_knapsack(fs, &order, unit, limit, |dir, before, reachable| {
+            let size = fs.size(dir) / unit;
+            for word in low / 64..=freed / 64 {
+                let mut added = before.shifted_word(word, size) & !reachable.bits[word];
+                while added != 0 {
+                    let amount = word * 64 + added.trailing_zeros() as usize;
+                    if (low..=freed).contains(&amount) {
+                        first[amount - low] = u32::try_from(dir).unwrap();
+                    }
+                    added &= added - 1;
+                }
+            }
+            first[freed - low] != u32::MAX
+        });
+        while low <= freed && 0 < freed {
+            let dir = first[freed - low] as usize;
+            plan.push(dir);
+            freed -= fs.size(dir) / unit;
+        }
+    }
+    plan.sort();
+    Some(plan)
 }
 
 fn main() {
     const INPUT: &str = include_str!("../inputs/7.txt");
-    let tree = parse_tree(INPUT).unwrap();
+    let fs = parse_tree(INPUT).unwrap();
     println!(
         "Sum of whatever: {}",
-        find_sum_of_max_size_directories(&tree, 100000)
+        find_sum_of_max_size_directories(&fs, 100000)
+    );
+
+    println!("Total size used: {}", fs.size(FileSystem::ROOT));
+    const DISK_SIZE: usize = 70000000;
+    const REQUIRED_SPACE: usize = 30000000;
+    let dir = find_smallest_eligible_directory_to_delete(&fs, DISK_SIZE, REQUIRED_SPACE).unwrap();
+    println!(
+        "The smallest directory that can be deleted to achieve the space increase is {} (size {})",
+        fs.path(dir),
+        fs.size(dir)
     );
 
-    println!("Total size used: {}", object_size(&tree));
+    let plan = plan_deletion(&fs, space_to_free(&fs, DISK_SIZE, REQUIRED_SPACE)).unwrap();
     println!(
-      "The smallest directory that can be deleted to achieve the space increase has a size of {}",
-      find_smallest_eligible_directory_to_delete(&tree).unwrap()
-  );
+        "Deleting several directories only requires deleting {} in total:",
+        plan.iter().map(|dir| fs.size(*dir)).sum::<usize>()
+    );
+    for dir in plan {
+        println!("  delete {} (size {})", fs.path(dir), fs.size(dir));
+    }
+
+    let args = std::env::args().skip(1).collect_vec();
+    match args.iter().map(String::as_str).collect_vec()[..] {
+        [] => (),
+        ["tree"] => println!("{}", format_tree(&fs)),
+        ["du"] => println!("{}", format_du(&fs)),
+        ["top", count] => {
+            let count = count.parse().unwrap();
+            for (title, directories) in [("directories", true), ("files", false)] {
+                println!("Largest {}:", title);
+                for id in find_largest(&fs, directories, count) {
+                    println!("{:>8}  {}", format_size(fs.size(id)), fs.path(id));
+                }
+            }
+        }
+        ref paths => {
+            for path in paths {
+                match fs.lookup(path) {
+                    Some(id) => println!("{} has a size of {}", fs.path(id), fs.size(id)),
+                    None => println!("{} does not exist", path),
+                }
+            }
+        }
+    }
 }
 
 const EXAMPLE_INPUT: &str = "$ cd /
@@ -130,39 +626,346 @@ $ ls
 
 #[test]
 fn test_parse() {
-    let expected = Object::Dir(vec![
-        Box::new(Object::File(14848514)),
-        Box::new(Object::File(8504156)),
-        // a
-        Box::new(Object::Dir(vec![
-            // e
-            Box::new(Object::File(29116)),
-            Box::new(Object::File(2557)),
-            Box::new(Object::File(62596)),
-            Box::new(Object::Dir(vec![Box::new(Object::File(584))])),
-        ])),
-        // d
-        Box::new(Object::Dir(vec![
-            Box::new(Object::File(4060174)),
-            Box::new(Object::File(8033020)),
-            Box::new(Object::File(5626152)),
-            Box::new(Object::File(7214296)),
-        ])),
-    ]);
-    assert_eq!(parse_tree(EXAMPLE_INPUT).unwrap(), expected);
+    let fs = parse_tree(EXAMPLE_INPUT).unwrap();
+    let root_names = fs
+        .children(FileSystem::ROOT)
+        .iter()
+        .map(|id| fs.nodes[*id].name.as_str())
+        .collect_vec();
+    assert_eq!(root_names, vec!["a", "b.txt", "c.dat", "d"]);
+
+    let e = fs.lookup("/a/e").unwrap();
+    assert!(fs.is_dir(e));
+    assert_eq!(fs.path(e), "/a/e");
+    assert_eq!(fs.size(e), 584);
+    assert_eq!(fs.nodes[e].parent, fs.lookup("/a"));
+    assert_eq!(
+        fs.nodes[fs.lookup("/a/e/i").unwrap()].object,
+        Object::File(584)
+    );
+
+    assert_eq!(fs.size(fs.lookup("/a").unwrap()), 94853);
+    assert_eq!(fs.size(fs.lookup("/d").unwrap()), 24933642);
+    assert_eq!(fs.size(FileSystem::ROOT), 48381165);
+    assert_eq!(fs.path(FileSystem::ROOT), "/");
+    assert_eq!(fs.lookup("/a/x"), None);
 }
 
 #[test]
 fn example_1() {
-    let tree = parse_tree(EXAMPLE_INPUT).unwrap();
-    assert_eq!(find_sum_of_max_size_directories(&tree, 100000), 95437);
+    let fs = parse_tree(EXAMPLE_INPUT).unwrap();
+    assert_eq!(find_sum_of_max_size_directories(&fs, 100000), 95437);
 }
 
 #[test]
 fn example_2() {
-    let tree = parse_tree(EXAMPLE_INPUT).unwrap();
+    let fs = parse_tree(EXAMPLE_INPUT).unwrap();
+    let dir = find_smallest_eligible_directory_to_delete(&fs, 70000000, 30000000).unwrap();
+    assert_eq!(fs.path(dir), "/d");
+    assert_eq!(fs.size(dir), 24933642);
+}
+
+#[test]
+fn test_plan_deletion() {
+    let fs = parse_tree(EXAMPLE_INPUT).unwrap();
+    let paths = |dirs: Vec<usize>| dirs.into_iter().map(|dir| fs.path(dir)).collect_vec();
+
+    assert_eq!(space_to_free(&fs, 70000000, 30000000), 8381165);
+    assert_eq!(paths(plan_deletion(&fs, 8381165).unwrap()), vec!["/d"]);
+    assert_eq!(paths(plan_deletion(&fs, 90000).unwrap()), vec!["/a"]);
+    assert_eq!(paths(plan_deletion(&fs, 500).unwrap()), vec!["/a/e"]);
+    assert_eq!(paths(plan_deletion(&fs, 0).unwrap()), Vec::<String>::new());
+    assert_eq!(space_to_free(&fs, 100000000, 30000000), 0);
+    assert_eq!(plan_deletion(&fs, 50000000), None);
+
+    // Two sibling directories together beat their common parent
+    let transcript = "$ cd /
+$ ls
+dir p
+5 r
+$ cd p
+$ ls
+dir x
+dir y
+dir z
+1000 big
+$ cd x
+$ ls
+60 a
+$ cd ../y
+$ ls
+50 b
+$ cd ../z
+$ ls
+100 c";
+    let fs = parse_tree(transcript).unwrap();
+    let paths = |dirs: Vec<usize>| dirs.into_iter().map(|dir| fs.path(dir)).collect_vec();
     assert_eq!(
-        find_smallest_eligible_directory_to_delete(&tree).unwrap(),
-        24933642
+        find_smallest_directory_to_delete_for_space(&fs, 110).map(|dir| fs.path(dir)),
+        Some("/p".to_string())
     );
+    assert_eq!(
+        paths(plan_deletion(&fs, 110).unwrap()),
+        vec!["/p/x", "/p/y"]
+    );
+    assert_eq!(paths(plan_deletion(&fs, 100).unwrap()), vec!["/p/z"]);
+}
+
+#[test]
+fn test_interpret_revisits() {
+    // Same as the example, but jumping around with absolute paths and listing some directories
+    // more than once
+    let transcript = "$ cd /
+$ ls
+dir a
+14848514 b.txt
+8504156 c.dat
+dir d
+$ cd a
+$ ls
+dir e
+29116 f
+2557 g
+62596 h.lst
+$ cd /a/e
+$ ls
+584 i
+$ cd /
+$ ls
+dir a
+14848514 b.txt
+8504156 c.dat
+dir d
+$ cd d
+$ ls
+4060174 j
+8033020 d.log
+5626152 d.ext
+7214296 k
+$ cd ../a/./e/..
+$ ls
+dir e
+29116 f
+2557 g
+62596 h.lst";
+    let (fs, inconsistencies) = interpret_transcript(transcript);
+    assert_eq!(inconsistencies, vec![]);
+    let expected = parse_tree(EXAMPLE_INPUT).unwrap();
+    assert_eq!(fs.nodes.len(), expected.nodes.len());
+    for id in 0..fs.nodes.len() {
+        let path = fs.path(id);
+        assert_eq!(fs.size(id), expected.size(expected.lookup(&path).unwrap()));
+    }
+}
+
+#[test]
+fn test_interpret_inconsistencies() {
+    let transcript = "$ cd /
+$ ls
+dir a
+10 b
+$ cd ..
+$ cd b
+$ ls
+20 b
+dir b
+$ cd x
+$ pwd
+5 c";
+    let (fs, inconsistencies) = interpret_transcript(transcript);
+    assert_eq!(
+        inconsistencies,
+        vec![
+            Inconsistency::AboveRoot { line: 5 },
+            Inconsistency::NotADirectory {
+                line: 6,
+                path: "/b".to_string()
+            },
+            Inconsistency::SizeChanged {
+                line: 8,
+                path: "/b".to_string(),
+                old: 10,
+                new: 20
+            },
+            Inconsistency::KindChanged {
+                line: 9,
+                path: "/b".to_string()
+            },
+            Inconsistency::UnlistedDirectory {
+                line: 10,
+                path: "/x".to_string()
+            },
+            Inconsistency::UnknownLine { line: 11 },
+            Inconsistency::OutputWithoutListing { line: 12 },
+        ]
+    );
+    assert_eq!(fs.size(FileSystem::ROOT), 25);
+    assert_eq!(fs.path(fs.lookup("/x/c").unwrap()), "/x/c");
+    assert!(parse_tree(transcript).is_err());
+}
+
+#[test]
+fn test_format_size() {
+    assert_eq!(format_size(584), "584B");
+    assert_eq!(format_size(2048), "2.0K");
+    assert_eq!(format_size(94853), "92.6K");
+    assert_eq!(format_size(24933642), "23.8M");
+}
+
+#[test]
+fn test_reports() {
+    let fs = parse_tree(EXAMPLE_INPUT).unwrap();
+    assert_eq!(
+        format_tree(&fs),
+        "/ [46.1M]
+├── a [92.6K]
+│   ├── e [584B]
+│   │   └── i [584B]
+│   ├── f [28.4K]
+│   ├── g [2.5K]
+│   └── h.lst [61.1K]
+├── b.txt [14.2M]
+├── c.dat [8.1M]
+└── d [23.8M]
+    ├── d.ext [5.4M]
+    ├── d.log [7.7M]
+    ├── j [3.9M]
+    └── k [6.9M]"
+    );
+    assert_eq!(format_du(&fs), "46.1M\t/\n23.8M\t/d\n92.6K\t/a\n584B\t/a/e");
+
+    let paths = |ids: Vec<usize>| ids.into_iter().map(|id| fs.path(id)).collect_vec();
+    assert_eq!(paths(find_largest(&fs, true, 2)), vec!["/", "/d"]);
+    assert_eq!(
+        paths(find_largest(&fs, false, 3)),
+        vec!["/b.txt", "/c.dat", "/d/d.log"]
+    );
+}
+
+#[test]
+fn test_deep_tree() {
+    const DEPTH: usize = 100000;
+    let mut transcript = String::from("$ cd /");
+    for _ in 0..DEPTH {
+        transcript += "\n$ ls\ndir d\n1 f\n$ cd d";
+    }
+    let fs = parse_tree(&transcript).unwrap();
+    assert_eq!(fs.size(FileSystem::ROOT), DEPTH);
+    assert_eq!(fs.directories().count(), DEPTH + 1);
+    assert_eq!(
+        find_sum_of_max_size_directories(&fs, DEPTH),
+        DEPTH * (DEPTH + 1) / 2
+    );
+
+    let dir = find_smallest_eligible_directory_to_delete(&fs, DEPTH + 10, 60).unwrap();
+    assert_eq!(fs.size(dir), 50);
+    assert_eq!(fs.path(dir).len(), 2 * (DEPTH - 50));
+    assert_eq!(plan_deletion(&fs, 50), Some(vec![dir]));
+    assert_eq!(fs.lookup(&fs.path(dir)), Some(dir));
+}
+
+#[test]
+fn test_plan_deletion_realistic_sizes() {
+    // Pseudo-random file sizes in the hundreds of kilobytes, like in the puzzle input
+    let mut seed = 12345u64;
+    let mut file_size = || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) as usize % 300000 + 1000
+    };
+
+    // A deep chain adding up to about 50M, which only ever allows deleting a single directory
+    const DEPTH: usize = 100000;
+    let mut transcript = String::from("$ cd /");
+    for _ in 0..DEPTH {
+        transcript += &format!("\n$ ls\ndir d\n{} f\n$ cd d", file_size() / 300);
+    }
+    let fs = parse_tree(&transcript).unwrap();
+    let to_free = space_to_free(&fs, 70000000, 30000000);
+    assert!(0 < to_free);
+    assert_eq!(
+        plan_deletion(&fs, to_free),
+        Some(vec![find_smallest_directory_to_delete_for_space(
+            &fs, to_free
+        )
+        .unwrap()])
+    );
+
+    // A deep chain with a small sibling directory at every level and a big file at the bottom,
+    // where no two directories waiting for their subtree share the same amounts. Only a few
+    // siblings are left out of the best plan.
+    const SIBLING_DEPTH: usize = 600;
+    let mut transcript = String::from("$ cd /");
+    let mut siblings_size = 0;
+    for _ in 0..SIBLING_DEPTH {
+        let size = file_size() / 250;
+        siblings_size += size;
+        transcript += &format!(
+            "\n$ ls\ndir s\ndir d\n$ cd s\n$ ls\n{} f\n$ cd ..\n$ cd d",
+            size
+        );
+    }
+    transcript += &format!("\n$ ls\n{} f", siblings_size);
+    let fs = parse_tree(&transcript).unwrap();
+    let to_free = siblings_size * 9 / 10;
+    let plan = plan_deletion(&fs, to_free).unwrap();
+    let freed = plan.iter().map(|dir| fs.size(*dir)).sum::<usize>();
+    assert!(SIBLING_DEPTH / 2 < plan.len());
+    assert!(to_free <= freed);
+    assert!(freed <= fs.size(find_smallest_directory_to_delete_for_space(&fs, to_free).unwrap()));
+    for dir in &plan {
+        let mut ancestor = fs.nodes[*dir].parent;
+        while let Some(id) = ancestor {
+            assert!(!plan.contains(&id));
+            ancestor = fs.nodes[id].parent;
+        }
+    }
+
+    // A small, bushy tree, checked against every possible set of directories
+    let mut transcript = String::from("$ cd /\n$ ls\ndir a\ndir b\ndir c");
+    for top in ["a", "b", "c"] {
+        transcript += &format!("\n$ cd /{}\n$ ls\ndir x\ndir y\ndir z", top);
+        transcript += &format!("\n{} f", file_size());
+        for sub in ["x", "y", "z"] {
+            transcript += &format!("\n$ cd /{}/{}\n$ ls", top, sub);
+            transcript += &format!("\n{} f\n{} g", file_size(), file_size());
+        }
+    }
+    let fs = parse_tree(&transcript).unwrap();
+    let dirs = fs.directories().collect_vec();
+    let is_inside = |mut dir: usize, ancestor: usize| {
+        while let Some(parent) = fs.nodes[dir].parent {
+            if parent == ancestor {
+                return true;
+            }
+            dir = parent;
+        }
+        false
+    };
+    for to_free in [1000, 250000, 700000, 1500000, 3000000] {
+        let best = (0..1u32 << dirs.len())
+            .map(|mask| {
+                dirs.iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask >> i & 1 == 1)
+                    .map(|(_, dir)| *dir)
+                    .collect_vec()
+            })
+            .filter(|picked| {
+                picked
+                    .iter()
+                    .all(|a| picked.iter().all(|b| !is_inside(*a, *b)))
+            })
+            .map(|picked| picked.iter().map(|dir| fs.size(*dir)).sum::<usize>())
+            .filter(|freed| to_free <= *freed)
+            .min();
+        let plan = plan_deletion(&fs, to_free);
+        assert_eq!(
+            plan.map(|plan| plan.iter().map(|dir| fs.size(*dir)).sum::<usize>()),
+            best,
+            "to free {}",
+            to_free
+        );
+    }
 }
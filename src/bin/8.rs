@@ -1,3 +1,5 @@
+use itertools::Itertools;
+
 type Grid = Vec<Vec<u32>>;
 
 fn parse_forest(input: &str) -> Grid {
@@ -10,37 +12,169 @@ fn parse_forest(input: &str) -> Grid {
 fn get_value_at((x, y): (i64, i64), grid: &Grid) -> Option<u32> {
     let i: usize = x.try_into().ok()?;
     let j: usize = y.try_into().ok()?;
-    grid.get(j).map(|row| row.get(i).copied()).flatten()
+    grid.get(j).and_then(|row| row.get(i).copied())
 }
 
-fn is_visible((x, y): (u32, u32), grid: &Grid) -> bool {
-    if let Some(current_tree) = get_value_at((x.into(), y.into()), grid) {
-        'direction: for (dx, dy) in [(1, 0), (0, 1), (-1, 0), (0, -1)] {
-            let mut i = 1;
-            while let Some(other_tree) =
-                get_value_at((i64::from(x) + dx * i, i64::from(y) + dy * i), grid)
-            {
-                if other_tree >= current_tree {
-                    continue 'direction;
+type Direction = (i64, i64);
+
+const CARDINAL_DIRECTIONS: [Direction; 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+const ALL_DIRECTIONS: [Direction; 8] = [
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+];
+
+// Walks away from `from` in a direction, returning every tree that can be seen by a viewer with
+// their eyes at `eye_height`. A tree is hidden when something in between is at least as tall as
+// both the tree and the viewer's eyes.
+fn trees_seen_along(
+    from: (i64, i64),
+    (dx, dy): Direction,
+    eye_height: i64,
+    grid: &Grid,
+) -> Vec<((i64, i64), bool)> {
+    let mut seen = Vec::new();
+    let mut tallest = None;
+    let mut pos = (from.0 + dx, from.1 + dy);
+    while let Some(tree) = get_value_at(pos, grid) {
+        let tree = i64::from(tree);
+        seen.push((pos, tallest.is_none_or(|t| t < tree.max(eye_height))));
+        tallest = tallest.max(Some(tree));
+        pos = (pos.0 + dx, pos.1 + dy);
+    }
+    seen
+}
+
+// The directions out of the ones given that a viewer outside the grid can see the tree from.
+// `eye_height` is how high up the viewer's eyes are, where 0 is ground level.
+fn visible_directions(
+    (x, y): (u32, u32),
+    directions: &[Direction],
+    eye_height: i64,
+    grid: &Grid,
+) -> Vec<Direction> {
+    let pos = (i64::from(x), i64::from(y));
+    let Some(current_tree) = get_value_at(pos, grid) else {
+        return Vec::new();
+    };
+    let current_tree = i64::from(current_tree);
+    directions
+        .iter()
+        .copied()
+        .filter(|(dx, dy)| {
+            let mut other = (pos.0 + dx, pos.1 + dy);
+            while let Some(other_tree) = get_value_at(other, grid) {
+                if current_tree.max(eye_height) <= i64::from(other_tree) {
+                    return false;
                 }
-                i += 1;
+                other = (other.0 + dx, other.1 + dy);
+            }
+            true
+        })
+        .collect()
+}
+
+// Every tree visible to a viewer standing on the tree at `observer`, looking in the given
+// directions. `height_offset` is how far above the top of that tree the viewer's eyes are.
+fn visible_from_observer(
+    (x, y): (u32, u32),
+    directions: &[Direction],
+    height_offset: i64,
+    grid: &Grid,
+) -> Vec<(i64, i64)> {
+    let observer = (i64::from(x), i64::from(y));
+    let Some(observer_tree) = get_value_at(observer, grid) else {
+        return Vec::new();
+    };
+    directions
+        .iter()
+        .flat_map(|dir| {
+            trees_seen_along(
+                observer,
+                *dir,
+                i64::from(observer_tree) + height_offset,
+                grid,
+            )
+        })
+        .filter_map(|(pos, seen)| seen.then_some(pos))
+        .collect()
+}
+
+fn is_visible(pos: (u32, u32), grid: &Grid) -> bool {
+    !visible_directions(pos, &CARDINAL_DIRECTIONS, 0, grid).is_empty()
+}
+
+// Every line of sight through the grid, as the coordinates passed when looking in from one of the
+// four edges, together with the index in `CARDINAL_DIRECTIONS` of where the viewer is. Produced one
+// line at a time to keep memory down for large forests.
+fn sight_lines(grid: &Grid) -> impl Iterator<Item = (usize, Vec<(usize, usize)>)> {
+    let height = grid.len();
+    let width = grid.first().map_or(0, Vec::len);
+    let rows = (0..height).map(move |j| (2, (0..width).map(|i| (i, j)).collect_vec()));
+    let columns = (0..width).map(move |i| (3, (0..height).map(|j| (i, j)).collect_vec()));
+    rows.chain(columns).flat_map(|(viewer, line)| {
+        let reversed = line.iter().rev().copied().collect_vec();
+        [(viewer, line), ((viewer + 2) % 4, reversed)]
+    })
+}
+
+// Sweeps inwards from every edge, keeping the tallest tree seen so far. Each tree gets a flag for
+// every direction in `CARDINAL_DIRECTIONS` it can be seen from.
+fn visible_directions_map(grid: &Grid) -> Vec<Vec<[bool; 4]>> {
+    let mut visible = grid
+        .iter()
+        .map(|row| vec![[false; 4]; row.len()])
+        .collect_vec();
+    for (viewer, line) in sight_lines(grid) {
+        let mut tallest = None;
+        for (i, j) in line {
+            if tallest.is_none_or(|t| t < grid[j][i]) {
+                visible[j][i][viewer] = true;
+                tallest = Some(grid[j][i]);
             }
-            return true;
         }
     }
-    false
+    visible
+}
+
+fn visibility_map(grid: &Grid) -> Vec<Vec<bool>> {
+    visible_directions_map(grid)
+        .into_iter()
+        .map(|row| row.into_iter().map(|dirs| dirs.contains(&true)).collect())
+        .collect()
 }
 
 fn count_visible(grid: &Grid) -> u32 {
-    let mut sum_visible = 0;
-    for (j, row) in grid.iter().enumerate() {
-        for (i, _) in row.iter().enumerate() {
-            if is_visible((i.try_into().unwrap(), j.try_into().unwrap()), &grid) {
-                sum_visible += 1;
+    visibility_map(grid)
+        .into_iter()
+        .flatten()
+        .filter(|visible| *visible)
+        .count()
+        .try_into()
+        .unwrap()
+}
+
+// Sweeps along every line of sight with a stack of the trees that can still block the view of the
+// trees further along. Anything shorter than the current tree can't block anything behind it.
+fn scenic_score_map(grid: &Grid) -> Vec<Vec<i64>> {
+    let mut scores = grid.iter().map(|row| vec![1; row.len()]).collect_vec();
+    for (_, line) in sight_lines(grid) {
+        let mut blocking: Vec<(usize, u32)> = Vec::new();
+        for (k, (i, j)) in line.into_iter().enumerate() {
+            while blocking.last().is_some_and(|(_, h)| *h < grid[j][i]) {
+                blocking.pop();
             }
+            let distance = blocking.last().map_or(k, |(last, _)| k - last);
+            scores[j][i] *= i64::try_from(distance).unwrap();
+            blocking.push((k, grid[j][i]));
         }
     }
-    sum_visible
+    scores
 }
 
 fn calculate_scenic_score((x, y): (u32, u32), grid: &Grid) -> i64 {
@@ -65,17 +199,70 @@ fn calculate_scenic_score((x, y): (u32, u32), grid: &Grid) -> i64 {
 }
 
 fn find_highest_scenic_score(grid: &Grid) -> Option<i64> {
-    grid.iter()
-        .enumerate()
-        .filter_map(|(j, row)| {
+    scenic_score_map(grid).into_iter().flatten().max()
+}
+
+fn format_visibility_mask(visible: &[Vec<bool>]) -> String {
+    visible
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|v| if *v { '#' } else { '.' })
+                .collect::<String>()
+        })
+        .join("\n")
+}
+
+// Scales every score linearly against the highest one, from ' ' up to '@'
+fn format_score_heatmap(scores: &[Vec<i64>]) -> String {
+    const RAMP: &[u8] = b" .:-=+*#%@";
+    let max = scores.iter().flatten().copied().max().unwrap_or(0).max(1);
+    let levels = i64::try_from(RAMP.len()).unwrap() - 1;
+    scores
+        .iter()
+        .map(|row| {
             row.iter()
-                .enumerate()
-                .map(|(i, _)| {
-                    calculate_scenic_score((i.try_into().unwrap(), j.try_into().unwrap()), &grid)
+                .map(|score| char::from(RAMP[usize::try_from(score * levels / max).unwrap()]))
+                .collect::<String>()
+        })
+        .join("\n")
+}
+
+// Plain (ASCII) greyscale image, with the scores scaled to fit in 0 to 255
+fn to_pgm(scores: &[Vec<i64>]) -> String {
+    let max = scores.iter().flatten().copied().max().unwrap_or(0).max(1);
+    let height = scores.len();
+    let width = scores.first().map_or(0, Vec::len);
+    let pixels = scores
+        .iter()
+        .map(|row| row.iter().map(|score| score * 255 / max).join(" "))
+        .join("\n");
+    format!("P2\n{} {}\n255\n{}\n", width, height, pixels)
+}
+
+// Plain (ASCII) color image of the forest, where visible trees are green and hidden trees are grey,
+// both brighter the taller the tree is
+fn to_ppm(grid: &Grid, visible: &[Vec<bool>]) -> String {
+    let height = grid.len();
+    let width = grid.first().map_or(0, Vec::len);
+    let pixels = grid
+        .iter()
+        .zip(visible)
+        .map(|(row, visible_row)| {
+            row.iter()
+                .zip(visible_row)
+                .map(|(tree, visible)| {
+                    let brightness = 75 + tree * 20;
+                    if *visible {
+                        format!("0 {} 0", brightness)
+                    } else {
+                        format!("{0} {0} {0}", brightness / 2)
+                    }
                 })
-                .max()
+                .join(" ")
         })
-        .max()
+        .join("\n");
+    format!("P3\n{} {}\n255\n{}\n", width, height, pixels)
 }
 
 fn main() {
@@ -90,6 +277,50 @@ fn main() {
         "The optimal tree has a scenic score of {}",
         find_highest_scenic_score(&forest).unwrap()
     );
+
+    let args = std::env::args().skip(1).collect_vec();
+    match args.iter().map(String::as_str).collect_vec()[..] {
+        ["heatmap"] => {
+            println!("{}\n", format_visibility_mask(&visibility_map(&forest)));
+            println!("{}", format_score_heatmap(&scenic_score_map(&forest)));
+        }
+        ["export", prefix] => {
+            let scores_path = format!("{}-scores.pgm", prefix);
+            let visible_path = format!("{}-visible.ppm", prefix);
+            std::fs::write(&scores_path, to_pgm(&scenic_score_map(&forest))).unwrap();
+            std::fs::write(&visible_path, to_ppm(&forest, &visibility_map(&forest))).unwrap();
+            println!("Wrote {} and {}", scores_path, visible_path);
+        }
+        ["directions", x, y] => {
+            let pos = (x.parse().unwrap(), y.parse().unwrap());
+            println!(
+                "The tree at {:?} can be seen from the directions {:?}",
+                pos,
+                visible_directions(pos, &ALL_DIRECTIONS, 0, &forest)
+            );
+        }
+        ["observer", x, y, offset] => {
+            let pos = (x.parse().unwrap(), y.parse().unwrap());
+            let seen =
+                visible_from_observer(pos, &ALL_DIRECTIONS, offset.parse().unwrap(), &forest);
+            println!(
+                "Standing on the tree at {:?}, {} trees can be seen: {:?}",
+                pos,
+                seen.len(),
+                seen
+            );
+        }
+        [x, y] => {
+            let pos = (x.parse().unwrap(), y.parse().unwrap());
+            println!(
+                "The tree at {:?} is {}visible and has a scenic score of {}",
+                pos,
+                if is_visible(pos, &forest) { "" } else { "not " },
+                calculate_scenic_score(pos, &forest)
+            );
+        }
+        _ => (),
+    }
 }
 
 const EXAMPLE_INPUT: &str = "30373
@@ -118,3 +349,132 @@ fn example_2() {
     assert_eq!(calculate_scenic_score((2, 3), &forest), 8);
     assert_eq!(find_highest_scenic_score(&forest).unwrap(), 8);
 }
+
+#[test]
+fn test_maps_match_per_tree() {
+    // Deterministic pseudo random forest, to compare against checking each tree by itself
+    let mut seed: u64 = 42;
+    let forest: Grid = (0..37)
+        .map(|_| {
+            (0..50)
+                .map(|_| {
+                    seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    u32::try_from(seed >> 60).unwrap() % 10
+                })
+                .collect()
+        })
+        .collect();
+
+    let visible = visibility_map(&forest);
+    let scores = scenic_score_map(&forest);
+    for j in 0..37 {
+        for i in 0..50 {
+            assert_eq!(visible[j][i], is_visible((i as u32, j as u32), &forest));
+            assert_eq!(
+                scores[j][i],
+                calculate_scenic_score((i as u32, j as u32), &forest)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_heatmaps() {
+    let forest = parse_forest(EXAMPLE_INPUT);
+    assert_eq!(
+        format_visibility_mask(&visibility_map(&forest)),
+        "#####
+###.#
+##.##
+#.#.#
+#####"
+    );
+    assert_eq!(
+        format_score_heatmap(&scenic_score_map(&forest)),
+        "     
+ .=. 
+ *.: 
+ .@- 
+     "
+    );
+}
+
+#[test]
+fn test_export() {
+    let forest = parse_forest("90\n19");
+    assert_eq!(
+        to_pgm(&scenic_score_map(&forest)),
+        "P2\n2 2\n255\n0 0\n0 0\n"
+    );
+    assert_eq!(
+        to_ppm(&forest, &visibility_map(&forest)),
+        "P3\n2 2\n255\n0 255 0 0 75 0\n0 95 0 0 255 0\n"
+    );
+
+    let forest = parse_forest(EXAMPLE_INPUT);
+    let pgm = to_pgm(&scenic_score_map(&forest));
+    let lines = pgm.lines().collect_vec();
+    assert_eq!(lines[..3], ["P2", "5 5", "255"]);
+    assert_eq!(lines[6], "0 31 255 95 0");
+}
+
+#[test]
+fn test_visible_directions() {
+    let forest = parse_forest(EXAMPLE_INPUT);
+    assert_eq!(
+        visible_directions((1, 1), &CARDINAL_DIRECTIONS, 0, &forest),
+        vec![(-1, 0), (0, -1)]
+    );
+    assert_eq!(
+        visible_directions((1, 1), &ALL_DIRECTIONS, 0, &forest),
+        vec![(1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)]
+    );
+    assert_eq!(
+        visible_directions((2, 2), &CARDINAL_DIRECTIONS, 0, &forest),
+        vec![]
+    );
+    assert_eq!(
+        visible_directions((2, 2), &CARDINAL_DIRECTIONS, 5, &forest),
+        vec![(1, 0)]
+    );
+
+    let map = visible_directions_map(&forest);
+    assert_eq!(map[1][1], [false, false, true, true]);
+    for (j, row) in map.iter().enumerate() {
+        for (i, flags) in row.iter().enumerate() {
+            let pos = (u32::try_from(i).unwrap(), u32::try_from(j).unwrap());
+            let expected = CARDINAL_DIRECTIONS
+                .iter()
+                .zip(flags)
+                .filter_map(|(dir, flag)| flag.then_some(*dir))
+                .collect_vec();
+            assert_eq!(
+                visible_directions(pos, &CARDINAL_DIRECTIONS, 0, &forest),
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn test_visible_from_observer() {
+    let forest = parse_forest(EXAMPLE_INPUT);
+    assert_eq!(
+        visible_from_observer((2, 3), &CARDINAL_DIRECTIONS, 0, &forest),
+        vec![(3, 3), (4, 3), (2, 4), (1, 3), (0, 3), (2, 2), (2, 1)]
+    );
+    assert_eq!(
+        visible_from_observer((2, 3), &CARDINAL_DIRECTIONS, -5, &forest),
+        vec![(3, 3), (4, 3), (2, 4), (1, 3), (2, 2), (2, 1)]
+    );
+    assert_eq!(
+        visible_from_observer((0, 0), &[(1, 1)], 0, &forest),
+        vec![(1, 1)]
+    );
+    assert_eq!(
+        visible_from_observer((0, 0), &[(1, 1)], 10, &forest).len(),
+        4
+    );
+}
@@ -1,26 +1,38 @@
-use std::{
-    collections::{HashMap, HashSet},
-    iter,
-};
-
-use itertools::Itertools;
+use std::collections::HashMap;
 
 type Pos = (i32, i32);
 
-fn parse_moves(input: &str) -> Vec<Pos> {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FollowRule {
+    // Knots stay put as long as they touch, diagonally included
+    Chebyshev,
+    // Knots stay put only while directly above, below or beside each other
+    Manhattan,
+    // Knots can drift up to the given number of cells further apart before being pulled along
+    Elastic(u32),
+}
+
+// Every line as a direction and the number of steps to take in it
+fn parse_move_list(input: &str) -> Result<Vec<(Pos, usize)>, String> {
     input
         .lines()
-        .map(|l| {
-            iter::repeat(match &l[..1] {
+        .enumerate()
+        .map(|(i, l)| {
+            let invalid = || format!("Invalid move \"{}\" on line {}", l, i + 1);
+            let (dir, count) = l.split_once(' ').ok_or_else(invalid)?;
+            let dir = match dir {
                 "R" => (1, 0),
                 "U" => (0, 1),
                 "L" => (-1, 0),
                 "D" => (0, -1),
-                _ => unimplemented!(),
-            })
-            .take(l[2..].parse().unwrap())
+                "UR" => (1, 1),
+                "UL" => (-1, 1),
+                "DR" => (1, -1),
+                "DL" => (-1, -1),
+                _ => return Err(invalid()),
+            };
+            Ok((dir, count.parse().map_err(|_| invalid())?))
         })
-        .flatten()
         .collect()
 }
 
@@ -28,6 +40,43 @@ fn is_neighbour(a: Pos, b: Pos) -> bool {
     (a.0 - b.0).abs() < 2 && (a.1 - b.1).abs() < 2
 }
 
+// Either "chebyshev", "manhattan" or "elastic:<slack>"
+fn parse_rule(input: &str) -> Option<FollowRule> {
+    match input.split_once(':') {
+        Some(("elastic", slack)) => Some(FollowRule::Elastic(slack.parse().ok()?)),
+        None if input == "chebyshev" => Some(FollowRule::Chebyshev),
+        None if input == "manhattan" => Some(FollowRule::Manhattan),
+        _ => None,
+    }
+}
+
+fn is_attached(rule: FollowRule, a: Pos, b: Pos) -> bool {
+    let (dx, dy) = ((a.0 - b.0).abs(), (a.1 - b.1).abs());
+    match rule {
+        FollowRule::Chebyshev => is_neighbour(a, b),
+        FollowRule::Manhattan => dx + dy < 2,
+        FollowRule::Elastic(slack) => dx.max(dy).unsigned_abs() < slack.saturating_add(2),
+    }
+}
+
+// Pulls the tail towards the head until they are attached again, passing every cell it steps
+// into to `moved`
+fn follow(rule: FollowRule, head: &Pos, tail: &Pos, mut moved: impl FnMut(Pos)) -> Pos {
+    let mut tail = *tail;
+    while !is_attached(rule, *head, tail) {
+        tail = match rule {
+            // Only straight steps, along whichever axis is furthest off
+            FollowRule::Manhattan if (head.0 - tail.0).abs() < (head.1 - tail.1).abs() => {
+                (tail.0, tail.1 + (head.1 - tail.1).signum())
+            }
+            FollowRule::Manhattan => (tail.0 + (head.0 - tail.0).signum(), tail.1),
+            _ => get_next_non_stationary_pos(head, &tail),
+        };
+        moved(tail);
+    }
+    tail
+}
+
 fn get_next_non_stationary_pos(head: &Pos, tail: &Pos) -> Pos {
     let dx = head.0 - tail.0;
     let dy = head.1 - tail.1;
@@ -40,60 +89,142 @@ fn get_next_non_stationary_pos(head: &Pos, tail: &Pos) -> Pos {
     }
 }
 
-// Gosh this could've been such a pretty function in Haskell...
-fn get_next_pos(rope: &Vec<Pos>, dir: Pos) -> Vec<Pos> {
-    let head = rope.first().unwrap();
-    let mut new_rope = rope.clone();
-    *new_rope.first_mut().unwrap() = (head.0 + dir.0, head.1 + dir.1);
-    for i in 0..new_rope.len() - 1 {
-        if let (Some(local_head), Some(local_tail)) = (new_rope.get(i), new_rope.get(i + 1)) {
-            *new_rope.get_mut(i + 1).unwrap() = if is_neighbour(*local_head, *local_tail) {
-                *local_tail
-            } else {
-                get_next_non_stationary_pos(local_head, local_tail)
-            }
+// Moves the rope one step in place. Once a knot stays put, so does every knot after it, which
+// lets the `moved` callback know exactly which knots ended up somewhere new. Knots taking more
+// than one step report every cell along the way.
+fn step(rope: &mut [Pos], dir: Pos, rule: FollowRule, mut moved: impl FnMut(usize, Pos)) {
+    rope[0] = (rope[0].0 + dir.0, rope[0].1 + dir.1);
+    moved(0, rope[0]);
+    for i in 1..rope.len() {
+        if is_attached(rule, rope[i - 1], rope[i]) {
+            break;
         }
+        rope[i] = follow(rule, &rope[i - 1], &rope[i], |pos| moved(i, pos));
+    }
+}
+
+// Simulates a rope of `rope_len` knots starting at the origin, counting how many times each of the
+// `tracked` knots enters every cell (the starting position counting as the first visit)
+fn simulate_rope(
+    moves: &[(Pos, usize)],
+    rope_len: usize,
+    tracked: &[usize],
+    rule: FollowRule,
+) -> Vec<HashMap<Pos, usize>> {
+    let mut rope = vec![(0, 0); rope_len];
+    let mut visits = vec![HashMap::from([((0, 0), 1)]); tracked.len()];
+    // Maps each knot to its slot in `visits`, if tracked
+    let mut slots = vec![None; rope_len];
+    for (slot, knot) in tracked.iter().enumerate() {
+        slots[*knot] = Some(slot);
+    }
+
+    for (dir, count) in moves {
+        for _ in 0..*count {
+            step(&mut rope, *dir, rule, |knot, pos| {
+                if let Some(slot) = slots[knot] {
+                    *visits[slot].entry(pos).or_insert(0) += 1;
+                }
+            });
+        }
+    }
+    visits
+}
+
+// Lower left and upper right corners of an area of the grid
+type Bounds = (Pos, Pos);
+
+fn fit_bounds<'a>(positions: impl IntoIterator<Item = &'a Pos>) -> Bounds {
+    let mut positions = positions.into_iter();
+    let first = positions.next().copied().unwrap_or((0, 0));
+    positions.fold((first, first), |(min, max), p| {
+        (
+            (min.0.min(p.0), min.1.min(p.1)),
+            (max.0.max(p.0), max.1.max(p.1)),
+        )
+    })
+}
+
+// Centers the view on the rope, growing it past `width` x `height` only if the rope doesn't fit
+fn follow_bounds(rope: &[Pos], width: i32, height: i32) -> Bounds {
+    let (min, max) = fit_bounds(rope);
+    let pad_x = (width - (max.0 - min.0 + 1)).max(0);
+    let pad_y = (height - (max.1 - min.1 + 1)).max(0);
+    (
+        (min.0 - pad_x / 2, min.1 - pad_y / 2),
+        (max.0 + pad_x - pad_x / 2, max.1 + pad_y - pad_y / 2),
+    )
+}
+
+fn knot_label(knot: usize, rope_len: usize) -> char {
+    match knot {
+        0 => 'H',
+        _ if knot == rope_len - 1 && (rope_len == 2 || 9 < knot) => 'T',
+        1..=9 => char::from_digit(knot as u32, 10).unwrap(),
+        _ => '*',
     }
-    new_rope
 }
 
-fn debug_pos(rope: &Vec<Pos>) {
-    const GRID_SIZE: i32 = 10;
-    for j in -GRID_SIZE..=GRID_SIZE {
+// Draws the area like the puzzle does, with up being positive y. Knots are drawn on top of the
+// start position, which in turn is drawn on top of the cells in `trail`.
+fn render(bounds: Bounds, rope: &[Pos], trail: Option<&HashMap<Pos, usize>>) -> String {
+    let ((min_x, min_y), (max_x, max_y)) = bounds;
+    let mut lines = Vec::new();
+    for j in (min_y..=max_y).rev() {
         let mut line = String::new();
-        for i in -GRID_SIZE..=GRID_SIZE {
-            if let Some(rope_pos) = rope.iter().position(|p| *p == (i, j)) {
-                line += &rope_pos.to_string();
-            } else if i == 0 && j == 0 {
-                line += "s";
+        for i in min_x..=max_x {
+            line.push(if let Some(knot) = rope.iter().position(|p| *p == (i, j)) {
+                knot_label(knot, rope.len())
+            } else if (i, j) == (0, 0) {
+                's'
+            } else if trail.is_some_and(|trail| trail.contains_key(&(i, j))) {
+                '#'
             } else {
-                line += ".";
-            }
+                '.'
+            });
         }
-        println!("{}", line);
+        lines.push(line);
     }
-    println!("\n");
+    lines.join("\n")
 }
 
-fn traverse_tail(input: &str, rope_len: usize) -> Vec<Pos> {
-    let mut rope_pos = vec![(0, 0); rope_len];
-    parse_moves(input)
-        .into_iter()
-        .map(|dir| {
-            rope_pos = get_next_pos(&rope_pos, dir);
-            // debug_pos(&rope_pos);
-            *rope_pos.last().unwrap()
+// The positions visited by the tail, drawn on an area fitting everywhere the rope has been
+fn render_trail_map(moves: &[(Pos, usize)], rope_len: usize, rule: FollowRule) -> String {
+    let visits = simulate_rope(moves, rope_len, &[0, rope_len - 1], rule);
+    let bounds = fit_bounds(visits.iter().flat_map(|v| v.keys()));
+    render(bounds, &[], visits.last())
+}
+
+// The state of the rope after each line of moves, with the view following the rope around
+fn render_moves(
+    moves: &[(Pos, usize)],
+    rope_len: usize,
+    rule: FollowRule,
+    width: i32,
+    height: i32,
+) -> Vec<String> {
+    let mut rope = vec![(0, 0); rope_len];
+    moves
+        .iter()
+        .map(|(dir, count)| {
+            for _ in 0..*count {
+                step(&mut rope, *dir, rule, |_, _| ());
+            }
+            render(follow_bounds(&rope, width, height), &rope, None)
         })
         .collect()
 }
 
 fn get_unique_tail_position_count(input: &str, rope_len: usize) -> i32 {
-    traverse_tail(input, rope_len)
-        .into_iter()
-        .collect::<HashSet<Pos>>()
-        .len()
-        .try_into()
-        .unwrap()
+    simulate_rope(
+        &parse_move_list(input).unwrap(),
+        rope_len,
+        &[rope_len - 1],
+        FollowRule::Chebyshev,
+    )[0]
+    .len()
+    .try_into()
+    .unwrap()
 }
 
 fn main() {
@@ -107,6 +238,41 @@ fn main() {
         "Unique positions visited by longer rope tail: {}",
         get_unique_tail_position_count(INPUT, 10)
     );
+
+    let moves = parse_move_list(INPUT).unwrap();
+    for rule in [
+        FollowRule::Chebyshev,
+        FollowRule::Manhattan,
+        FollowRule::Elastic(1),
+        FollowRule::Elastic(3),
+    ] {
+        println!(
+            "Using the {:?} rule, the longer rope tail visits {} positions",
+            rule,
+            simulate_rope(&moves, 10, &[9], rule)[0].len()
+        );
+    }
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let rule = args
+        .get(2)
+        .map_or(Some(FollowRule::Chebyshev), |rule| parse_rule(rule))
+        .expect("Invalid follow rule");
+    match args.iter().map(String::as_str).take(2).collect::<Vec<_>>()[..] {
+        ["trail", rope_len] => println!(
+            "{}",
+            render_trail_map(&moves, rope_len.parse().unwrap(), rule)
+        ),
+        ["animate", rope_len] => {
+            for (frame, line) in render_moves(&moves, rope_len.parse().unwrap(), rule, 40, 20)
+                .into_iter()
+                .zip(INPUT.lines())
+            {
+                println!("== {} ==\n{}\n", line, frame);
+            }
+        }
+        _ => (),
+    }
 }
 
 const EXAMPLE_INPUT: &str = "R 4
@@ -129,11 +295,24 @@ U 20";
 
 #[test]
 fn test_parse_moves() {
-    assert_eq!(parse_moves("L 1\nD 1"), vec![(-1, 0), (0, -1)]);
     assert_eq!(
-        parse_moves("R 2\nU 3"),
-        vec![(1, 0), (1, 0), (0, 1), (0, 1), (0, 1)]
+        parse_move_list("L 1\nD 1").unwrap(),
+        vec![((-1, 0), 1), ((0, -1), 1)]
     );
+    assert_eq!(
+        parse_move_list("R 2\nU 3").unwrap(),
+        vec![((1, 0), 2), ((0, 1), 3)]
+    );
+    for (input, error) in [
+        ("R 1\nUD 2", "Invalid move \"UD 2\" on line 2"),
+        ("RR 1", "Invalid move \"RR 1\" on line 1"),
+        ("UUR 1", "Invalid move \"UUR 1\" on line 1"),
+        ("X 1", "Invalid move \"X 1\" on line 1"),
+        ("R", "Invalid move \"R\" on line 1"),
+        ("R -1", "Invalid move \"R -1\" on line 1"),
+    ] {
+        assert_eq!(parse_move_list(input), Err(error.to_string()));
+    }
 }
 
 #[test]
@@ -165,3 +344,186 @@ fn example_2() {
     assert_eq!(get_unique_tail_position_count(EXAMPLE_INPUT, 10), 1);
     assert_eq!(get_unique_tail_position_count(EXAMPLE_INPUT_2, 10), 36);
 }
+
+#[test]
+fn test_visit_counts() {
+    let visits = simulate_rope(
+        &parse_move_list(EXAMPLE_INPUT).unwrap(),
+        2,
+        &[0, 1],
+        FollowRule::Chebyshev,
+    );
+    assert_eq!(visits[0].values().sum::<usize>(), 1 + 24);
+    assert_eq!(visits[0].get(&(2, 4)), Some(&1));
+    assert_eq!(visits[0].get(&(2, 2)), Some(&2));
+    assert_eq!(visits[1].len(), 13);
+    assert_eq!(visits[1].get(&(0, 0)), Some(&1));
+    assert!(visits[1].contains_key(&(2, 2)));
+    assert_eq!(visits[1].get(&(0, 4)), None);
+
+    let visits = simulate_rope(
+        &parse_move_list(EXAMPLE_INPUT_2).unwrap(),
+        10,
+        &(0..10).collect::<Vec<_>>(),
+        FollowRule::Chebyshev,
+    );
+    assert_eq!(visits.len(), 10);
+    assert_eq!(visits[9].len(), 36);
+    assert!(visits.windows(2).all(|w| w[1].len() <= w[0].len()));
+}
+
+#[test]
+fn test_long_rope() {
+    // Spiral outwards so that the whole rope keeps moving
+    let moves = (1..=40)
+        .flat_map(|i| {
+            [(1, 0), (0, 1), (-1, 0), (0, -1)]
+                .into_iter()
+                .map(move |dir| (dir, i * 10))
+        })
+        .collect::<Vec<_>>();
+    let visits = simulate_rope(&moves, 1000, &[0, 499, 999], FollowRule::Chebyshev);
+    assert_eq!(visits[0].values().sum::<usize>(), 1 + 4 * 10 * 40 * 41 / 2);
+    assert!(visits[2].len() < visits[1].len());
+    assert!(visits[1].len() < visits[0].len());
+}
+
+#[test]
+fn test_render_trail_map() {
+    assert_eq!(
+        render_trail_map(
+            &parse_move_list(EXAMPLE_INPUT).unwrap(),
+            2,
+            FollowRule::Chebyshev
+        ),
+        "..##..
+...##.
+.####.
+....#.
+s###.."
+    );
+    assert_eq!(
+        render_trail_map(
+            &parse_move_list(EXAMPLE_INPUT_2).unwrap(),
+            10,
+            FollowRule::Chebyshev
+        ),
+        "..........................
+..........................
+..........................
+..........................
+..........................
+..........................
+..........................
+..........................
+..........................
+#.........................
+#.............###.........
+#............#...#........
+.#..........#.....#.......
+..#..........#.....#......
+...#........#.......#.....
+....#......s.........#....
+.....#..............#.....
+......#............#......
+.......#..........#.......
+........#........#........
+.........########........."
+    );
+}
+
+#[test]
+fn test_render() {
+    let moves = parse_move_list(EXAMPLE_INPUT_2).unwrap();
+    let visits = simulate_rope(&moves, 10, &[0], FollowRule::Chebyshev);
+    let bounds = fit_bounds(visits[0].keys());
+    let mut rope = vec![(0, 0); 10];
+    for _ in 0..5 {
+        step(&mut rope, (1, 0), FollowRule::Chebyshev, |_, _| ());
+    }
+    assert_eq!(
+        render(bounds, &rope, None).lines().nth(15),
+        Some("...........54321H.........")
+    );
+
+    let frames = render_moves(
+        &parse_move_list(EXAMPLE_INPUT).unwrap(),
+        2,
+        FollowRule::Chebyshev,
+        6,
+        5,
+    );
+    assert_eq!(frames[0], "......\n......\n..TH..\n......\n......");
+    assert_eq!(
+        frames.last().unwrap(),
+        "......\n......\n..TH..\n......\n.s...."
+    );
+}
+
+#[test]
+fn test_diagonal_moves() {
+    assert_eq!(
+        parse_move_list("UR 2\nDL 1\nR 1").unwrap(),
+        vec![((1, 1), 2), ((-1, -1), 1), ((1, 0), 1)]
+    );
+    let visits = simulate_rope(
+        &parse_move_list("UR 2").unwrap(),
+        2,
+        &[1],
+        FollowRule::Chebyshev,
+    );
+    assert_eq!(visits[0], HashMap::from([((0, 0), 1), ((1, 1), 1)]));
+}
+
+#[test]
+fn test_follow_rules() {
+    let moves = parse_move_list("R 3\nU 1").unwrap();
+    let tail = |rule| {
+        let mut cells = simulate_rope(&moves, 2, &[1], rule)[0]
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        cells.sort();
+        cells
+    };
+    assert_eq!(tail(FollowRule::Chebyshev), vec![(0, 0), (1, 0), (2, 0)]);
+    assert_eq!(
+        tail(FollowRule::Manhattan),
+        vec![(0, 0), (1, 0), (2, 0), (3, 0)]
+    );
+    assert_eq!(tail(FollowRule::Elastic(1)), vec![(0, 0), (1, 0)]);
+    assert_eq!(tail(FollowRule::Elastic(0)), tail(FollowRule::Chebyshev));
+
+    // Diagonal head moves stretch a Manhattan rope by two, so the tail has to take two steps
+    let visits = simulate_rope(
+        &parse_move_list("UR 1").unwrap(),
+        2,
+        &[1],
+        FollowRule::Manhattan,
+    );
+    assert_eq!(visits[0].len(), 2);
+    let visits = simulate_rope(
+        &parse_move_list("UR 2").unwrap(),
+        2,
+        &[1],
+        FollowRule::Manhattan,
+    );
+    // (0, 0), (1, 0), then (1, 1) on the way to (2, 1)
+    assert_eq!(visits[0].len(), 4);
+    // The tail steps through (1, 0) on its way to (2, 0), which counts as a visit
+    let visits = simulate_rope(
+        &parse_move_list("R 1\nUR 1").unwrap(),
+        2,
+        &[1],
+        FollowRule::Manhattan,
+    );
+    assert_eq!(
+        visits[0],
+        HashMap::from([((0, 0), 1), ((1, 0), 1), ((2, 0), 1)])
+    );
+
+    assert_eq!(parse_rule("manhattan"), Some(FollowRule::Manhattan));
+    assert_eq!(parse_rule("elastic:3"), Some(FollowRule::Elastic(3)));
+    assert_eq!(parse_rule("elastic"), None);
+    assert_eq!(parse_rule("elastic:-2"), None);
+}